@@ -0,0 +1,657 @@
+use std::{fmt::Display, mem};
+
+use anyhow::{bail, Context, Result};
+use bumpalo::Bump;
+use dynasmrt::{dynasm, DynamicLabel, DynasmApi, DynasmLabelApi};
+use log::trace;
+
+use crate::config::{Config, EofPolicy, Overflow, TapeMode};
+use crate::hir::{BfOp, HirGen};
+use crate::ir::{IrLike, Spanned};
+use crate::jit::x86_64::{self, Assembler};
+use crate::jit::{JitContext, CELL_BYTES, READ_ERROR, READ_UNCHANGED};
+use crate::lir::{LirGen, LirOp};
+use crate::state::BrainfuckState;
+
+/// Where executables are loaded, the usual address for ones which aren't position independent
+const BASE_ADDR: u64 = 0x40_0000;
+
+const ELF_HEADER_LEN: u16 = 64;
+const PROGRAM_HEADER_LEN: u16 = 56;
+const PROGRAM_HEADERS: u16 = 2;
+
+/// The headers are loaded along with the code, which comes straight after them
+const CODE_ADDR: u64 = BASE_ADDR + (ELF_HEADER_LEN + PROGRAM_HEADERS * PROGRAM_HEADER_LEN) as u64;
+
+/// Linux x86-64 syscall numbers
+const SYS_READ: i32 = 0;
+const SYS_WRITE: i32 = 1;
+const SYS_MMAP: i32 = 9;
+const SYS_EXIT_GROUP: i32 = 231;
+
+/// Syscalls return errors as negated `errno`s, and this one means try again
+const EINTR: i32 = 4;
+
+/// `mmap` flags for a private, zeroed mapping which only takes up memory once it is touched
+const PROT_READ_WRITE: i32 = 0x3;
+const MAP_ANONYMOUS_NORESERVE: i32 = 0x2 | 0x20 | 0x4000;
+
+/// The runtime keeps the error to report after the [`JitContext`], as a pointer to one of the
+/// [`Message`]s, the op it came from and the number shown in the middle of the message
+const ERROR_MESSAGE: i32 = mem::size_of::<JitContext>() as i32;
+const ERROR_OP: i32 = ERROR_MESSAGE + 8;
+const ERROR_VALUE: i32 = ERROR_OP + 8;
+
+/// The tape comes after the context and the error, in the same mapping
+const TAPE: i32 = ERROR_VALUE + 8;
+
+/// Stands in for the op of an error which isn't down to any one op
+const NO_OP: i32 = -1;
+
+/// Register numbers for the dynamic registers we use
+const RAX: u8 = 0;
+const RSI: u8 = 6;
+const R9: u8 = 9;
+
+/// Ahead-of-time compiles programs to standalone Linux x86-64 executables
+///
+/// The program itself is the same code the x86-64 JIT emits. The executable brings along its own
+/// tape and a small runtime written in assembly, which does I/O with raw `read` and `write`
+/// syscalls and reports errors much like rustfuck does, so doesn't depend on anything else
+pub struct Aot;
+
+impl Aot {
+    /// Optimises `program` and compiles it to an executable, returning the contents of the file
+    pub fn compile(program: &[Spanned<BfOp>], config: &Config) -> Result<Vec<u8>> {
+        let arena = Bump::new();
+        let hir = HirGen::gen(program, config);
+        let lir = LirGen::gen_ir(&hir, config, &arena)?;
+
+        Self::build(&lir, config)
+    }
+
+    /// Compiles `program` to an executable, returning the contents of the file
+    ///
+    /// A fixed tape is `tape_len` cells as usual. An infinite one is `max_tape_len` cells with the
+    /// pointer starting in the middle, so can only grow half that far in either direction
+    pub fn build(program: &[Spanned<LirOp>], config: &Config) -> Result<Vec<u8>> {
+        trace!("Building Lir: {}", program.to_compact());
+
+        if config.is_limited() {
+            bail!("Step and time limits aren't supported in executables");
+        }
+
+        config.check_state(&BrainfuckState::new())?;
+
+        let (len, start) = match config.tape {
+            TapeMode::Fixed => (config.tape_len, 0),
+            TapeMode::Infinite => (config.max_tape_len, config.max_tape_len / 2),
+        };
+
+        let tape_bytes = len
+            .checked_mul(CELL_BYTES as usize)
+            .and_then(|bytes| i64::try_from(bytes).ok())
+            .context("The tape is too large for an executable")?;
+        let start = start as i64 * CELL_BYTES as i64;
+
+        let mut asm = Assembler::new(CODE_ADDR as usize);
+        let runtime = Runtime::new(&mut asm, config);
+
+        let entry = asm.offset();
+        runtime.emit_start(&mut asm, tape_bytes, start);
+
+        dynasm!(asm
+            ; .arch x64
+            ; .align 16
+            ; =>runtime.main
+        );
+
+        x86_64::emit(&mut asm, program, config)?;
+
+        runtime.emit_routines(&mut asm, config);
+        runtime.emit_data(&mut asm, program);
+
+        let code = asm.finalize().expect("asm gen failed");
+
+        Ok(elf(&code, CODE_ADDR + entry.0 as u64))
+    }
+}
+
+/// An error the runtime can report, optionally with a number shown between `text` and `after`
+///
+/// Emitted after the code as the length of `text` followed by its text, then the same for `after`,
+/// with a length of -1 if there's no number to show
+struct Message {
+    label: DynamicLabel,
+    text: String,
+    after: Option<String>,
+}
+
+impl Message {
+    fn new(asm: &mut Assembler, text: impl Display) -> Self {
+        Self {
+            label: asm.new_dynamic_label(),
+            text: format!("Error: {text}"),
+            after: None,
+        }
+    }
+
+    fn with_value(asm: &mut Assembler, text: impl Display, after: impl Display) -> Self {
+        Self {
+            after: Some(after.to_string()),
+            ..Self::new(asm, text)
+        }
+    }
+}
+
+/// The labels of everything the executable is made up of besides the program itself
+///
+/// Messages which end in "at" are followed by the source location of the op which failed
+struct Runtime {
+    main: DynamicLabel,
+    read: DynamicLabel,
+    flush: DynamicLabel,
+    fault: DynamicLabel,
+    overflow: DynamicLabel,
+    write_all: DynamicLabel,
+    spans: DynamicLabel,
+    span_text: DynamicLabel,
+    newline: DynamicLabel,
+    no_memory: Message,
+    pointer: Message,
+    cell_overflow: Message,
+    eof: Message,
+    io: Message,
+    flush_failed: Message,
+}
+
+impl Runtime {
+    fn new(asm: &mut Assembler, config: &Config) -> Self {
+        // How many cells an infinite tape would need isn't known, as it doesn't grow
+        let pointer = match config.tape {
+            TapeMode::Fixed => Message::with_value(
+                asm,
+                "Tried to move the pointer to cell ",
+                format_args!(", outside of the tape (0..{}), at ", config.tape_len),
+            ),
+            TapeMode::Infinite => Message::new(
+                asm,
+                format_args!(
+                    "The tape would need more than the maximum of {} cells, at ",
+                    config.max_tape_len
+                ),
+            ),
+        };
+
+        Self {
+            main: asm.new_dynamic_label(),
+            read: asm.new_dynamic_label(),
+            flush: asm.new_dynamic_label(),
+            fault: asm.new_dynamic_label(),
+            overflow: asm.new_dynamic_label(),
+            write_all: asm.new_dynamic_label(),
+            spans: asm.new_dynamic_label(),
+            span_text: asm.new_dynamic_label(),
+            newline: asm.new_dynamic_label(),
+            no_memory: Message::new(asm, "Couldn't allocate the tape"),
+            pointer,
+            cell_overflow: Message::new(asm, "Cell overflowed at "),
+            eof: Message::new(asm, "Ran out of input at "),
+            io: Message::new(asm, "I/O failed at "),
+            flush_failed: Message::new(asm, "Flushing output failed"),
+        }
+    }
+
+    /// Emits the entry point, which maps the context and a tape of `tape_bytes` bytes, runs the
+    /// program starting `start` bytes into the tape, then flushes the output, reports any error
+    /// and exits
+    fn emit_start(&self, asm: &mut Assembler, tape_bytes: i64, start: i64) {
+        dynasm!(asm
+            ; .arch x64
+            ; xor edi, edi
+            ; mov rsi, QWORD tape_bytes
+            ; add rsi, TAPE
+            ; mov edx, PROT_READ_WRITE
+            ; mov r10d, MAP_ANONYMOUS_NORESERVE
+            ; mov r8, -1
+            ; xor r9d, r9d
+            ; mov eax, SYS_MMAP
+            ; syscall
+            // Errors are the last 4095 values
+            ; cmp rax, -4096
+            ; ja >no_memory
+            ; mov r12, rax
+            ; lea rax, [=>self.read]
+            ; mov QWORD [r12 + JitContext::READ], rax
+            ; lea rax, [=>self.flush]
+            ; mov QWORD [r12 + JitContext::FLUSH], rax
+            ; lea rax, [=>self.fault]
+            ; mov QWORD [r12 + JitContext::FAULT], rax
+            ; lea rax, [=>self.overflow]
+            ; mov QWORD [r12 + JitContext::OVERFLOW], rax
+            ; lea rax, [r12 + TAPE]
+            ; mov QWORD [r12 + JitContext::TAPE_LO], rax
+            ; mov rcx, QWORD tape_bytes
+            ; add rcx, rax
+            ; mov QWORD [r12 + JitContext::TAPE_HI], rcx
+            ; mov rdi, QWORD start
+            ; add rdi, rax
+            ; mov rsi, r12
+            ; call =>self.main
+            ; mov rbx, rax
+            // Whatever was output before an abort is still worth showing
+            ; mov rdi, r12
+            ; mov rsi, NO_OP
+            ; call =>self.flush
+            ; or rbx, rax
+            ; mov rsi, QWORD [r12 + ERROR_MESSAGE]
+            ; test rsi, rsi
+            ; jz >exit
+            ; mov rdx, QWORD [rsi]
+            ; lea r13, [rsi + rdx + 8]
+            ; add rsi, 8
+            ; mov edi, 2
+            ; call =>self.write_all
+            ; cmp QWORD [r13], -1
+            ; je >span
+            // Write out the number backwards from the end of a buffer on the stack, negating it
+            // as unsigned so even the most negative number works
+            ; sub rsp, 32
+            ; lea rsi, [rsp + 32]
+            ; mov rax, QWORD [r12 + ERROR_VALUE]
+            ; test rax, rax
+            ; jns >digits
+            ; neg rax
+            ; digits:
+            ; mov ecx, 10
+            ; xor edx, edx
+            ; div rcx
+            ; add dl, b'0' as i8
+            ; sub rsi, 1
+            ; mov BYTE [rsi], dl
+            ; test rax, rax
+            ; jnz <digits
+            ; cmp QWORD [r12 + ERROR_VALUE], 0
+            ; jge >positive
+            ; sub rsi, 1
+            ; mov BYTE [rsi], b'-' as i8
+            ; positive:
+            ; lea rdx, [rsp + 32]
+            ; sub rdx, rsi
+            ; mov edi, 2
+            ; call =>self.write_all
+            ; add rsp, 32
+            ; mov rdx, QWORD [r13]
+            ; lea rsi, [r13 + 8]
+            ; mov edi, 2
+            ; call =>self.write_all
+            ; span:
+            ; mov rax, QWORD [r12 + ERROR_OP]
+            ; cmp rax, NO_OP
+            ; je >newline
+            ; lea rcx, [=>self.spans]
+            ; mov esi, DWORD [rcx + rax * 4]
+            ; mov edx, DWORD [rcx + rax * 4 + 4]
+            ; sub edx, esi
+            ; lea rcx, [=>self.span_text]
+            ; add rsi, rcx
+            ; mov edi, 2
+            ; call =>self.write_all
+            ; newline:
+            ; lea rsi, [=>self.newline]
+            ; mov edx, 1
+            ; mov edi, 2
+            ; call =>self.write_all
+            ; exit:
+            ; xor edi, edi
+            ; test rbx, rbx
+            ; setnz dil
+            ; mov eax, SYS_EXIT_GROUP
+            ; syscall
+            ; no_memory:
+            ; lea rsi, [=>self.no_memory.label]
+            ; mov rdx, QWORD [rsi]
+            ; add rsi, 8
+            ; mov edi, 2
+            ; call =>self.write_all
+            ; mov ebx, 1
+            ; jmp <newline
+        );
+    }
+
+    /// Emits the functions behind the [`JitContext`]'s function pointers, along with a helper
+    /// which writes `rdx` bytes at `rsi` to the file descriptor in `edi`
+    ///
+    /// Each keeps the context in `r8` and the op in `r9`, which syscalls leave alone
+    fn emit_routines(&self, asm: &mut Assembler, config: &Config) {
+        // `read` flushes first, as the interpreters do
+        dynasm!(asm
+            ; .arch x64
+            ; =>self.read
+            ; push rsi
+            ; push rdi
+            ; call =>self.flush
+            ; pop r8
+            ; pop r9
+            ; test rax, rax
+            ; jnz >error
+            ; retry:
+            ; xor edi, edi
+            // Read into the red zone, below the stack pointer
+            ; lea rsi, [rsp - 8]
+            ; mov edx, 1
+            ; mov eax, SYS_READ
+            ; syscall
+            ; cmp rax, -EINTR
+            ; je <retry
+            ; cmp rax, 1
+            ; je >byte
+            ; test rax, rax
+            ; jnz >failed
+        );
+
+        match config.eof {
+            EofPolicy::Unchanged => dynasm!(asm
+                ; .arch x64
+                ; mov rax, READ_UNCHANGED as i32
+                ; ret
+            ),
+            EofPolicy::Zero => dynasm!(asm
+                ; .arch x64
+                ; xor eax, eax
+                ; ret
+            ),
+            EofPolicy::MinusOne => dynasm!(asm
+                ; .arch x64
+                ; mov eax, u32::MAX as i32
+                ; ret
+            ),
+            EofPolicy::Error => {
+                record_error(asm, &self.eof, R9, None);
+                dynasm!(asm
+                    ; .arch x64
+                    ; jmp >error
+                );
+            }
+        }
+
+        dynasm!(asm
+            ; .arch x64
+            ; byte:
+            ; movzx eax, BYTE [rsp - 8]
+            ; ret
+            ; failed:
+        );
+        record_error(asm, &self.io, R9, None);
+        dynasm!(asm
+            ; .arch x64
+            ; error:
+            ; mov rax, READ_ERROR as i32
+            ; ret
+        );
+
+        dynasm!(asm
+            ; .arch x64
+            ; =>self.flush
+            ; mov r8, rdi
+            ; mov r9, rsi
+            ; mov rdx, QWORD [r8 + JitContext::OUT_LEN]
+            ; mov QWORD [r8 + JitContext::OUT_LEN], 0
+            ; lea rsi, [r8 + JitContext::OUT_BUF]
+            ; mov edi, 1
+            ; call =>self.write_all
+            ; test rax, rax
+            ; jz >done
+            ; cmp r9, NO_OP
+            ; je >at_exit
+        );
+        record_error(asm, &self.io, R9, None);
+        dynasm!(asm
+            ; .arch x64
+            ; jmp >failed
+            ; at_exit:
+        );
+        record_error(asm, &self.flush_failed, R9, None);
+        dynasm!(asm
+            ; .arch x64
+            ; failed:
+            ; mov eax, 1
+            ; done:
+            ; ret
+        );
+
+        dynasm!(asm
+            ; .arch x64
+            ; =>self.fault
+            // Work out which cell the target is, keeping the target itself in `rdi`
+            ; mov r8, rdi
+            ; mov r9, rcx
+            ; mov rdi, rdx
+            ; mov rax, rdx
+            ; sub rax, QWORD [r8 + JitContext::TAPE_LO]
+            ; mov ecx, CELL_BYTES as i32
+            ; cqo
+            ; idiv rcx
+        );
+
+        // A fixed tape where the pointer doesn't trap puts the pointer back on it when it moves
+        // off, but an access to another cell off the tape is still an error
+        if config.tape == TapeMode::Fixed && config.pointer_overflow != Overflow::Trap {
+            dynasm!(asm
+                ; .arch x64
+                ; cmp rdi, rsi
+                ; jne >off_tape
+            );
+
+            match config.pointer_overflow {
+                Overflow::Wrap => dynasm!(asm
+                    ; .arch x64
+                    ; mov rcx, QWORD config.tape_len as i64
+                    ; cqo
+                    ; idiv rcx
+                    ; test rdx, rdx
+                    ; jns >wrapped
+                    ; add rdx, rcx
+                    ; wrapped:
+                    ; imul rdx, rdx, CELL_BYTES as i32
+                    ; mov rax, QWORD [r8 + JitContext::TAPE_LO]
+                    ; add rax, rdx
+                    ; ret
+                ),
+                _ => dynasm!(asm
+                    ; .arch x64
+                    ; test rax, rax
+                    ; mov rax, QWORD [r8 + JitContext::TAPE_LO]
+                    ; js >clamped
+                    ; mov rax, QWORD [r8 + JitContext::TAPE_HI]
+                    ; sub rax, CELL_BYTES as i32
+                    ; clamped:
+                    ; ret
+                ),
+            }
+
+            dynasm!(asm
+                ; .arch x64
+                ; off_tape:
+            );
+        }
+
+        record_error(asm, &self.pointer, R9, Some(RAX));
+        dynasm!(asm
+            ; .arch x64
+            ; xor eax, eax
+            ; ret
+        );
+
+        dynasm!(asm
+            ; .arch x64
+            ; =>self.overflow
+            ; mov r8, rdi
+        );
+        record_error(asm, &self.cell_overflow, RSI, None);
+        dynasm!(asm
+            ; .arch x64
+            ; ret
+        );
+
+        // Returns non-zero if writing failed
+        dynasm!(asm
+            ; .arch x64
+            ; =>self.write_all
+            ; retry:
+            ; test rdx, rdx
+            ; jz >written
+            ; mov eax, SYS_WRITE
+            ; syscall
+            ; cmp rax, -EINTR
+            ; je <retry
+            ; test rax, rax
+            ; jle >failed
+            ; add rsi, rax
+            ; sub rdx, rax
+            ; jmp <retry
+            ; written:
+            ; xor eax, eax
+            ; ret
+            ; failed:
+            ; mov eax, 1
+            ; ret
+        );
+    }
+
+    /// Emits the messages, and the source location of every op in `program` as a table of where
+    /// each one's text starts, with one extra entry for where the last one ends
+    fn emit_data(&self, asm: &mut Assembler, program: &[Spanned<LirOp>]) {
+        for message in [
+            &self.no_memory,
+            &self.pointer,
+            &self.cell_overflow,
+            &self.eof,
+            &self.io,
+            &self.flush_failed,
+        ] {
+            dynasm!(asm
+                ; .arch x64
+                ; .align 8
+                ; =>message.label
+                ; .qword message.text.len() as i64
+                ; .bytes message.text.as_bytes()
+            );
+
+            match &message.after {
+                Some(after) => dynasm!(asm
+                    ; .arch x64
+                    ; .qword after.len() as i64
+                    ; .bytes after.as_bytes()
+                ),
+                None => dynasm!(asm
+                    ; .arch x64
+                    ; .qword -1
+                ),
+            }
+        }
+
+        let text = program
+            .iter()
+            .map(|op| op.span.to_string())
+            .collect::<Vec<_>>();
+
+        dynasm!(asm
+            ; .arch x64
+            ; .align 4
+            ; =>self.spans
+        );
+
+        let mut start = 0;
+        for span in text.iter() {
+            dynasm!(asm
+                ; .arch x64
+                ; .dword start
+            );
+
+            start += span.len() as i32;
+        }
+
+        dynasm!(asm
+            ; .arch x64
+            ; .dword start
+            ; =>self.span_text
+            ; .bytes text.concat().as_bytes()
+            ; =>self.newline
+            ; .bytes b"\n"
+        );
+    }
+}
+
+/// Records `message` as the error to report along with the op in `op`, and the number in `value`
+/// if the message shows one, unless there already is one as only the first error is reported
+///
+/// Expects the context in `r8`
+fn record_error(asm: &mut Assembler, message: &Message, op: u8, value: Option<u8>) {
+    dynasm!(asm
+        ; .arch x64
+        ; cmp QWORD [r8 + ERROR_MESSAGE], 0
+        ; jne >recorded
+        ; lea r10, [=>message.label]
+        ; mov QWORD [r8 + ERROR_MESSAGE], r10
+        ; mov QWORD [r8 + ERROR_OP], Rq(op)
+    );
+
+    if let Some(value) = value {
+        dynasm!(asm
+            ; .arch x64
+            ; mov QWORD [r8 + ERROR_VALUE], Rq(value)
+        );
+    }
+
+    dynasm!(asm
+        ; .arch x64
+        ; recorded:
+    );
+}
+
+/// Wraps `code`, which was assembled to run from [`CODE_ADDR`], into an ELF executable starting at
+/// `entry`
+fn elf(code: &[u8], entry: u64) -> Vec<u8> {
+    let len = CODE_ADDR - BASE_ADDR + code.len() as u64;
+    let mut file = Vec::with_capacity(len as usize);
+
+    // 64-bit, little endian, version 1, System V ABI
+    file.extend_from_slice(b"\x7fELF\x02\x01\x01\x00");
+    file.extend_from_slice(&[0; 8]);
+    // An executable for x86-64
+    file.extend_from_slice(&2u16.to_le_bytes());
+    file.extend_from_slice(&0x3eu16.to_le_bytes());
+    file.extend_from_slice(&1u32.to_le_bytes());
+    file.extend_from_slice(&entry.to_le_bytes());
+    // The program headers follow straight on, and there are no section headers
+    file.extend_from_slice(&u64::from(ELF_HEADER_LEN).to_le_bytes());
+    file.extend_from_slice(&0u64.to_le_bytes());
+    file.extend_from_slice(&0u32.to_le_bytes());
+    file.extend_from_slice(&ELF_HEADER_LEN.to_le_bytes());
+    file.extend_from_slice(&PROGRAM_HEADER_LEN.to_le_bytes());
+    file.extend_from_slice(&PROGRAM_HEADERS.to_le_bytes());
+    file.extend_from_slice(&[0; 6]);
+
+    // The whole file is loaded as one readable and executable segment, everything writable is
+    // mapped by the runtime
+    program_header(&mut file, 1, 0x4 | 0x1, BASE_ADDR, len, 0x1000);
+    // `PT_GNU_STACK`, to ask for a stack which isn't executable
+    program_header(&mut file, 0x6474_e551, 0x4 | 0x2, 0, 0, 16);
+
+    file.extend_from_slice(code);
+
+    file
+}
+
+/// Appends a program header for a segment of `len` bytes, loaded from the start of the file to
+/// `addr`
+fn program_header(file: &mut Vec<u8>, kind: u32, flags: u32, addr: u64, len: u64, align: u64) {
+    file.extend_from_slice(&kind.to_le_bytes());
+    file.extend_from_slice(&flags.to_le_bytes());
+    file.extend_from_slice(&0u64.to_le_bytes());
+
+    for field in [addr, addr, len, len, align] {
+        file.extend_from_slice(&field.to_le_bytes());
+    }
+}
@@ -0,0 +1,69 @@
+use std::io::{Read, Write};
+
+use anyhow::Result;
+use bumpalo::Bump;
+
+use crate::{
+    config::Config,
+    hir::{BfOp, HirInterpreter},
+    ir::Spanned,
+    jit::JitProgram,
+    lir::LirInterpreter,
+    parser::BfInterpreter,
+    state::BrainfuckState,
+};
+
+/// A way of executing brainfuck
+///
+/// A value of a backend is a program compiled for it, ready to run any number of times
+pub trait Backend<'a> {
+    /// Compiles a parsed program, with any data it needs allocated in `arena`
+    fn compile(program: &[Spanned<BfOp>], config: &Config, arena: &'a Bump) -> Result<Self>
+    where
+        Self: Sized;
+
+    /// Runs the program starting from `state`, leaving it as the program left it
+    fn run(
+        &self,
+        state: &mut BrainfuckState,
+        input: &mut dyn Read,
+        output: &mut dyn Write,
+    ) -> Result<()>;
+}
+
+/// Every backend, from least to most optimised
+#[derive(Debug, PartialEq, Eq, Copy, Clone, clap::ValueEnum)]
+pub enum BackendKind {
+    /// Executes brainfuck as-is
+    Bf,
+    /// Fuses runs of `+-` and `<>`
+    Hir,
+    /// Additionally optimises loops
+    Lir,
+    /// Compiles the optimised program to machine code
+    Jit,
+}
+
+impl BackendKind {
+    pub const ALL: [BackendKind; 4] = [
+        BackendKind::Bf,
+        BackendKind::Hir,
+        BackendKind::Lir,
+        BackendKind::Jit,
+    ];
+
+    /// Compiles `program` with this backend
+    pub fn compile<'a>(
+        self,
+        program: &[Spanned<BfOp>],
+        config: &Config,
+        arena: &'a Bump,
+    ) -> Result<Box<dyn Backend<'a> + 'a>> {
+        Ok(match self {
+            BackendKind::Bf => Box::new(BfInterpreter::compile(program, config, arena)?),
+            BackendKind::Hir => Box::new(HirInterpreter::compile(program, config, arena)?),
+            BackendKind::Lir => Box::new(LirInterpreter::compile(program, config, arena)?),
+            BackendKind::Jit => Box::new(JitProgram::compile(program, config, arena)?),
+        })
+    }
+}
@@ -0,0 +1,215 @@
+use std::{
+    io::{self, Read},
+    time::Duration,
+};
+
+use crate::error::RuntimeError;
+use crate::state::BrainfuckState;
+
+/// The length of the tape in the original brainfuck implementation
+pub const DEFAULT_TAPE_LEN: usize = 30_000;
+
+/// The most cells a tape can have unless told otherwise, which take up 256MiB
+pub const DEFAULT_MAX_TAPE_LEN: usize = 1 << 26;
+
+/// Settings which change how a program behaves, shared by every backend
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub struct Config {
+    /// What `,` does at the end of input
+    pub eof: EofPolicy,
+    /// How many bits each cell holds
+    pub cell_width: CellWidth,
+    /// What happens when a cell goes past 0 or its largest value
+    pub cell_overflow: Overflow,
+    /// Whether the tape has ends, or grows on demand in both directions
+    pub tape: TapeMode,
+    /// What happens when the pointer moves off either end of a fixed tape
+    pub pointer_overflow: Overflow,
+    /// How many cells are on a fixed tape
+    pub tape_len: usize,
+    /// The most cells any tape can have, so a runaway program fails rather than using up all the
+    /// memory there is
+    ///
+    /// Built executables and translated programs can't grow an infinite tape, so start with all of
+    /// it, half either side of the pointer. A program on one can only go half this far in either
+    /// direction. The other backends grow the tape as needed, but split the room either side of
+    /// the pointer differently, so a program using nearly all of it may fail on some before others
+    pub max_tape_len: usize,
+    /// How many steps a program can take before it is stopped, see [`Budget`](crate::limit::Budget)
+    pub step_limit: Option<u64>,
+    /// How long a program can run for before it is stopped
+    pub time_limit: Option<Duration>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            eof: EofPolicy::default(),
+            cell_width: CellWidth::default(),
+            cell_overflow: Overflow::Wrap,
+            tape: TapeMode::default(),
+            pointer_overflow: Overflow::Trap,
+            tape_len: DEFAULT_TAPE_LEN,
+            max_tape_len: DEFAULT_MAX_TAPE_LEN,
+            step_limit: None,
+            time_limit: None,
+        }
+    }
+}
+
+impl Config {
+    /// Adds `delta` to `cell`, or returns `None` if the result is out of range and overflow traps
+    #[inline]
+    pub fn add_to_cell(&self, cell: u32, delta: i128) -> Option<u32> {
+        let max = self.cell_width.max();
+
+        match self.cell_overflow {
+            // Only the low bits of the delta can make a difference
+            Overflow::Wrap => Some(self.cell_width.wrap(cell.wrapping_add(delta as u32))),
+            Overflow::Saturate => Some((cell as i128 + delta).clamp(0, max as i128) as u32),
+            Overflow::Trap => u32::try_from(cell as i128 + delta)
+                .ok()
+                .filter(|&value| value <= max),
+        }
+    }
+
+    /// Whether a program can be stopped for running too long
+    pub fn is_limited(&self) -> bool {
+        self.step_limit.is_some() || self.time_limit.is_some()
+    }
+
+    /// Whether moving the pointer always takes it exactly where it was told to go, or fails, rather
+    /// than wrapping or clamping it back on to the tape
+    pub fn moves_are_exact(&self) -> bool {
+        self.tape == TapeMode::Infinite || self.pointer_overflow == Overflow::Trap
+    }
+
+    /// Where the pointer ends up when it is moved to `pos` on a fixed tape, or `None` if that is
+    /// off the tape and overflow traps
+    #[inline]
+    pub fn pointer_at(&self, pos: isize) -> Option<usize> {
+        let len = isize::try_from(self.tape_len).ok()?;
+
+        match self.pointer_overflow {
+            Overflow::Wrap => pos.checked_rem_euclid(len).map(|pos| pos as usize),
+            Overflow::Saturate => (len > 0).then(|| pos.clamp(0, len - 1) as usize),
+            Overflow::Trap => (0..len).contains(&pos).then_some(pos as usize),
+        }
+    }
+
+    /// Fails if `state` can't be run with this config, because its pointer is off a fixed tape or
+    /// the tape is already too large
+    pub fn check_state(&self, state: &BrainfuckState) -> Result<(), RuntimeError> {
+        if self.tape == TapeMode::Fixed && state.pos >= self.tape_len {
+            return Err(RuntimeError::StartOutOfRange {
+                cell: state.pos,
+                len: self.tape_len,
+            });
+        }
+
+        let len = match self.tape {
+            TapeMode::Fixed => self.tape_len,
+            TapeMode::Infinite => state.cells.len().max(state.pos + 1),
+        };
+
+        if len > self.max_tape_len {
+            return Err(RuntimeError::TapeTooLarge {
+                span: None,
+                len,
+                max: self.max_tape_len,
+            });
+        }
+
+        Ok(())
+    }
+}
+
+/// Whether the tape has ends
+#[derive(Debug, Default, PartialEq, Eq, Copy, Clone, clap::ValueEnum)]
+pub enum TapeMode {
+    /// `tape_len` cells, with `pointer_overflow` deciding what happens at either end
+    #[default]
+    Fixed,
+    /// Grows on demand to the left as well as the right, so the pointer can never leave it
+    Infinite,
+}
+
+/// What happens when a cell or the pointer goes past the end of its range
+#[derive(Debug, PartialEq, Eq, Copy, Clone, clap::ValueEnum)]
+pub enum Overflow {
+    /// Carry on from the other end
+    Wrap,
+    /// Stay at the end
+    Saturate,
+    /// Stop the program with an error
+    Trap,
+}
+
+/// How many bits each cell holds
+///
+/// Cells are always stored as `u32`s, narrower cells just never use the upper bits
+#[derive(Debug, Default, PartialEq, Eq, Copy, Clone, clap::ValueEnum)]
+pub enum CellWidth {
+    #[default]
+    #[value(name = "8")]
+    U8,
+    #[value(name = "16")]
+    U16,
+    #[value(name = "32")]
+    U32,
+}
+
+impl CellWidth {
+    pub const fn bits(self) -> u32 {
+        match self {
+            CellWidth::U8 => 8,
+            CellWidth::U16 => 16,
+            CellWidth::U32 => 32,
+        }
+    }
+
+    /// The largest value a cell can hold, which is also what -1 wraps around to
+    pub const fn max(self) -> u32 {
+        u32::MAX >> (32 - self.bits())
+    }
+
+    /// Truncates `value` to fit in a cell
+    pub const fn wrap(self, value: u32) -> u32 {
+        value & self.max()
+    }
+}
+
+/// What `,` does to the current cell once the input is exhausted
+#[derive(Debug, Default, PartialEq, Eq, Copy, Clone, clap::ValueEnum)]
+pub enum EofPolicy {
+    /// Leave the cell as it was
+    #[default]
+    Unchanged,
+    /// Set the cell to 0
+    Zero,
+    /// Set the cell to -1 (all bits set)
+    MinusOne,
+    /// Stop the program with an error
+    Error,
+}
+
+impl EofPolicy {
+    /// Reads a byte for `,`, returning what to store in the current cell, if anything
+    ///
+    /// -1 is returned as [`u32::MAX`], so should be wrapped to the width of a cell. Running out of
+    /// input with [`EofPolicy::Error`] is an [`io::ErrorKind::UnexpectedEof`] error
+    pub fn read(self, input: &mut dyn Read) -> io::Result<Option<u32>> {
+        let mut buff = [0; 1];
+
+        match input.read_exact(&mut buff) {
+            Ok(()) => Ok(Some(buff[0].into())),
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => match self {
+                EofPolicy::Unchanged => Ok(None),
+                EofPolicy::Zero => Ok(Some(0)),
+                EofPolicy::MinusOne => Ok(Some(u32::MAX)),
+                EofPolicy::Error => Err(err),
+            },
+            Err(err) => Err(err),
+        }
+    }
+}
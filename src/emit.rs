@@ -0,0 +1,58 @@
+use anyhow::{bail, Result};
+use bumpalo::Bump;
+use log::trace;
+
+use crate::config::Config;
+use crate::hir::{BfOp, HirGen};
+use crate::ir::{IrLike, Spanned};
+use crate::lir::LirGen;
+use crate::state::BrainfuckState;
+
+mod c;
+mod llvm;
+mod rust;
+mod wasm;
+
+/// Every language optimised programs can be translated to
+///
+/// The translations behave like the other backends do under the same [`Config`], except that they
+/// don't support step and time limits, and an infinite tape is `max_tape_len` cells with the
+/// pointer starting in the middle, so can only grow half that far in either direction
+#[derive(Debug, PartialEq, Eq, Copy, Clone, clap::ValueEnum)]
+pub enum EmitKind {
+    /// Portable C, using `getchar` and `putchar` for I/O
+    C,
+    /// LLVM IR, for `opt` to optimise or `clang` to compile for any target
+    ///
+    /// The IR uses opaque pointers, so needs LLVM 15 or later
+    Llvm,
+    /// A Rust function taking `impl Read` and `impl Write`, to `include!` in another crate
+    Rust,
+    /// A WebAssembly module, with the tape in its memory and I/O through imported `getchar` and
+    /// `putchar`
+    Wasm,
+}
+
+impl EmitKind {
+    /// Optimises `program` and translates it, returning the contents of the file
+    pub fn emit(self, program: &[Spanned<BfOp>], config: &Config) -> Result<Vec<u8>> {
+        if config.is_limited() {
+            bail!("Step and time limits aren't supported in emitted code");
+        }
+
+        config.check_state(&BrainfuckState::new())?;
+
+        let arena = Bump::new();
+        let hir = HirGen::gen(program, config);
+        let lir = LirGen::gen_ir(&hir, config, &arena)?;
+
+        trace!("Emitting Lir as {self:?}: {}", lir.to_compact());
+
+        match self {
+            EmitKind::C => c::emit(&lir, config),
+            EmitKind::Llvm => llvm::emit(&lir, config),
+            EmitKind::Rust => rust::emit(&lir, config),
+            EmitKind::Wasm => wasm::emit(&lir, config),
+        }
+    }
+}
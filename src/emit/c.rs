@@ -0,0 +1,242 @@
+use std::fmt::Write;
+
+use anyhow::Result;
+
+use crate::config::{CellWidth, Config, EofPolicy, Overflow, TapeMode};
+use crate::ir::Spanned;
+use crate::lir::LirOp;
+
+/// Translates `program` into a C file
+///
+/// The pointer is an index into a static tape, kept in a local so the compiler knows stores to the
+/// tape can't change it, and every access goes through `at`, which applies
+/// the pointer overflow policy and fails with the source location of the op if it has to.
+/// Arithmetic wraps for free in unsigned types, the other overflow policies go through `add`
+pub(super) fn emit(program: &[Spanned<LirOp>], config: &Config) -> Result<Vec<u8>> {
+    let mut out = String::new();
+
+    prelude(&mut out, config)?;
+
+    let wraps = config.cell_overflow == Overflow::Wrap;
+    let mut depth = 1;
+
+    for Spanned { op, span } in program {
+        let indent = "    ".repeat(depth);
+        let span = format!("\"{span}\"");
+
+        match *op {
+            LirOp::Move(delta) => writeln!(out, "{indent}p = at(p, {delta}, {span});")?,
+            LirOp::OffsetModify(delta, offset) if wraps => writeln!(
+                out,
+                "{indent}tape[{}] += {}u;",
+                index(offset, &span),
+                config.cell_width.wrap(delta as u32)
+            )?,
+            LirOp::OffsetModify(delta, offset) => writeln!(
+                out,
+                "{indent}add({}, {delta}, {span});",
+                index(offset, &span)
+            )?,
+            LirOp::WriteZero => writeln!(out, "{indent}tape[p] = 0;")?,
+            LirOp::Hop(delta) => {
+                writeln!(out, "{indent}while (tape[p]) p = at(p, {delta}, {span});")?
+            }
+            LirOp::MoveCell(delta) => {
+                writeln!(out, "{indent}if (tape[p]) {{")?;
+
+                if wraps {
+                    writeln!(out, "{indent}    tape[at(p, {delta}, {span})] += tape[p];")?;
+                } else {
+                    writeln!(
+                        out,
+                        "{indent}    add(at(p, {delta}, {span}), tape[p], {span});"
+                    )?;
+                }
+
+                writeln!(out, "{indent}    tape[p] = 0;")?;
+                writeln!(out, "{indent}}}")?;
+            }
+            LirOp::MulAdd(targets) => {
+                writeln!(out, "{indent}if (tape[p]) {{")?;
+                writeln!(out, "{indent}    uint32_t v = tape[p];")?;
+
+                for &(offset, factor) in targets {
+                    if wraps {
+                        // Only the low bits of the product end up in the cell
+                        writeln!(
+                            out,
+                            "{indent}    tape[at(p, {offset}, {span})] += (cell)(v * {}u);",
+                            config.cell_width.wrap(factor as u32)
+                        )?;
+                    } else {
+                        writeln!(
+                            out,
+                            "{indent}    add(at(p, {offset}, {span}), (long long)v * {factor}, {span});"
+                        )?;
+                    }
+                }
+
+                writeln!(out, "{indent}    tape[p] = 0;")?;
+                writeln!(out, "{indent}}}")?;
+            }
+            LirOp::In => writeln!(out, "{indent}input(&tape[p], {span});")?,
+            LirOp::Out => writeln!(out, "{indent}output(tape[p], {span});")?,
+            LirOp::BrFor => {
+                writeln!(out, "{indent}while (tape[p]) {{")?;
+                depth += 1;
+            }
+            LirOp::BrBack => {
+                depth -= 1;
+                writeln!(out, "{}}}", "    ".repeat(depth))?;
+            }
+            LirOp::Meta(comment) => {
+                writeln!(out, "{indent}/* {} */", comment.replace("*/", "* /"))?
+            }
+        }
+    }
+
+    writeln!(out)?;
+    writeln!(
+        out,
+        "    if (fflush(stdout) != 0) fail(\"Flushing output failed\", \"\");"
+    )?;
+    writeln!(out, "    return 0;")?;
+    writeln!(out, "}}")?;
+
+    Ok(out.into_bytes())
+}
+
+/// Writes everything up to the start of `main`: the tape, and the helpers the body calls
+fn prelude(out: &mut String, config: &Config) -> Result<()> {
+    let (len, start) = match config.tape {
+        TapeMode::Fixed => (config.tape_len, 0),
+        TapeMode::Infinite => (config.max_tape_len, config.max_tape_len / 2),
+    };
+
+    let cell = match config.cell_width {
+        CellWidth::U8 => "uint8_t",
+        CellWidth::U16 => "uint16_t",
+        CellWidth::U32 => "uint32_t",
+    };
+
+    write!(
+        out,
+        r#"/* Generated by rustfuck */
+#include <stdint.h>
+#include <stdio.h>
+#include <stdlib.h>
+
+#define TAPE_LEN {len}LL
+#define CELL_MAX {max}LL
+
+typedef {cell} cell;
+
+static cell tape[TAPE_LEN];
+
+/* Shows what was output so far, then reports an error and exits */
+static void fail(const char *message, const char *span) {{
+    fflush(stdout);
+    fprintf(stderr, "Error: %s%s\n", message, span);
+    exit(1);
+}}
+
+/* The index of the cell `offset` away from the pointer at `p` */
+static inline long long at(long long p, long long offset, const char *span) {{
+    long long i = p + offset;
+"#,
+        max = config.cell_width.max(),
+    )?;
+
+    match (config.tape, config.pointer_overflow) {
+        (TapeMode::Infinite, _) => writeln!(
+            out,
+            r#"    if (i < 0 || i >= TAPE_LEN) {{
+        fail("The tape would need more than the maximum of {len} cells, at ", span);
+    }}"#
+        )?,
+        (TapeMode::Fixed, Overflow::Wrap) => writeln!(
+            out,
+            r#"    i %= TAPE_LEN;
+    if (i < 0) i += TAPE_LEN;"#
+        )?,
+        (TapeMode::Fixed, Overflow::Saturate) => writeln!(
+            out,
+            r#"    if (i < 0) i = 0;
+    if (i >= TAPE_LEN) i = TAPE_LEN - 1;"#
+        )?,
+        (TapeMode::Fixed, Overflow::Trap) => writeln!(
+            out,
+            r#"    if (i < 0 || i >= TAPE_LEN) {{
+        fflush(stdout);
+        fprintf(stderr,
+                "Error: Tried to move the pointer to cell %lld, outside of the tape (0..%lld), at %s\n",
+                i, TAPE_LEN, span);
+        exit(1);
+    }}"#
+        )?,
+    }
+
+    writeln!(
+        out,
+        r#"    return i;
+}}
+"#
+    )?;
+
+    if config.cell_overflow != Overflow::Wrap {
+        let overflowed = match config.cell_overflow {
+            Overflow::Trap => r#"fail("Cell overflowed at ", span);"#,
+            _ => "value = value < 0 ? 0 : CELL_MAX;",
+        };
+
+        writeln!(
+            out,
+            r#"/* Adds `delta` to the cell at `i`, when cells don't wrap */
+static inline void add(long long i, long long delta, const char *span) {{
+    long long value = (long long)tape[i] + delta;
+    if (value < 0 || value > CELL_MAX) {overflowed}
+    tape[i] = (cell)value;
+}}
+"#
+        )?;
+    }
+
+    let eof = match config.eof {
+        EofPolicy::Unchanged => "",
+        EofPolicy::Zero => " else *to = 0;",
+        EofPolicy::MinusOne => " else *to = (cell)-1;",
+        EofPolicy::Error => r#" else fail("Ran out of input at ", span);"#,
+    };
+
+    writeln!(
+        out,
+        r#"/* Reads a byte into a cell, flushing any output first */
+static inline void input(cell *to, const char *span) {{
+    int c;
+    if (fflush(stdout) != 0) fail("I/O failed at ", span);
+    c = getchar();
+    if (c != EOF) *to = (cell)c;
+    else if (ferror(stdin)) fail("I/O failed at ", span);{eof}
+}}
+
+/* Outputs the low byte of a cell */
+static inline void output(cell value, const char *span) {{
+    if (putchar((unsigned char)value) == EOF) fail("I/O failed at ", span);
+}}
+
+int main(void) {{
+    long long p = {start};
+"#
+    )?;
+
+    Ok(())
+}
+
+/// The index of the cell `offset` away from the pointer, which is always on the tape so only
+/// needs checking if `offset` is non-zero
+fn index(offset: isize, span: &str) -> String {
+    match offset {
+        0 => "p".into(),
+        offset => format!("at(p, {offset}, {span})"),
+    }
+}
@@ -0,0 +1,618 @@
+use std::collections::HashMap;
+use std::fmt::Write;
+
+use anyhow::Result;
+
+use crate::config::{Config, EofPolicy, Overflow, TapeMode};
+use crate::error::RuntimeError;
+use crate::ir::Spanned;
+use crate::lir::LirOp;
+
+/// Translates `program` into an LLVM IR module, in the textual `.ll` format with opaque pointers,
+/// which need LLVM 15 or later
+///
+/// The tape is a global array, loops are basic blocks and I/O calls `getchar` and `putchar`, with
+/// the same helpers the C translation has for applying the overflow policies and failing. The
+/// pointer lives in an `alloca` and is loaded before every use, leaving `mem2reg` to turn it into
+/// registers, so the IR stays close to the LIR and doesn't need optimising by hand
+pub(super) fn emit(program: &[Spanned<LirOp>], config: &Config) -> Result<Vec<u8>> {
+    let mut emitter = Emitter {
+        config,
+        cell: format!("i{}", config.cell_width.bits()),
+        out: String::new(),
+        temps: 0,
+        blocks: 0,
+        spans: HashMap::new(),
+    };
+
+    emitter.prelude()?;
+
+    let mut loops = Vec::new();
+
+    for Spanned { op, span: location } in program {
+        let span = emitter.span(&location.to_string());
+
+        match *op {
+            LirOp::Move(delta) => {
+                let index = emitter.index(delta, &span)?;
+                writeln!(emitter.out, "  store i64 {index}, ptr %p")?;
+            }
+            LirOp::OffsetModify(delta, offset) => {
+                let cell = emitter.cell_ptr(offset, &span)?;
+                emitter.add(&cell, Addend::Const(delta), &span)?;
+            }
+            LirOp::WriteZero => emitter.write_zero()?,
+            LirOp::Hop(delta) => {
+                let (test, after) = emitter.loop_blocks()?;
+                let index = emitter.index(delta, &span)?;
+                writeln!(emitter.out, "  store i64 {index}, ptr %p")?;
+                writeln!(emitter.out, "  br label %{test}")?;
+                writeln!(emitter.out, "{after}:")?;
+            }
+            LirOp::MoveCell(delta) => emitter.add_current(&[(delta, 1)], &span)?,
+            LirOp::MulAdd(targets) => emitter.add_current(targets, &span)?,
+            LirOp::In => {
+                let cell = emitter.current()?;
+                writeln!(emitter.out, "  call void @input(ptr {cell}, ptr {span})")?;
+            }
+            LirOp::Out => {
+                let value = emitter.load_current()?;
+                writeln!(
+                    emitter.out,
+                    "  call void @output({} {value}, ptr {span})",
+                    emitter.cell
+                )?;
+            }
+            LirOp::BrFor => loops.push((*location, emitter.loop_blocks()?)),
+            LirOp::BrBack => {
+                let (_, (test, after)) = loops
+                    .pop()
+                    .ok_or(RuntimeError::UnmatchedBracket { span: *location })?;
+                writeln!(emitter.out, "  br label %{test}")?;
+                writeln!(emitter.out, "{after}:")?;
+            }
+            LirOp::Meta(comment) => writeln!(emitter.out, "  ; {}", comment.replace('\n', " "))?,
+        }
+    }
+
+    // Only the innermost unclosed loop is reported, as `tree::build` does
+    if let Some((open, _)) = loops.pop() {
+        return Err(RuntimeError::UnmatchedBracket { span: open }.into());
+    }
+
+    let Emitter { mut out, spans, .. } = emitter;
+
+    write!(
+        out,
+        r#"  %flushed = call i32 @fflush(ptr null)
+  %flush_failed = icmp ne i32 %flushed, 0
+  br i1 %flush_failed, label %flush_failed.block, label %done
+
+flush_failed.block:
+  call void @fail(ptr @flush_failed, ptr @empty)
+  unreachable
+
+done:
+  ret i32 0
+}}
+"#
+    )?;
+
+    // Spans are kept in globals so the helpers can report them, in order so the output is stable
+    let mut spans = spans.into_iter().collect::<Vec<_>>();
+    spans.sort_by_key(|&(_, n)| n);
+
+    if !spans.is_empty() {
+        writeln!(out)?;
+    }
+
+    for (span, n) in spans {
+        writeln!(out, "{}", string(&format!("span.{n}"), &span))?;
+    }
+
+    Ok(out.into_bytes())
+}
+
+/// What [`Emitter::add`] adds to a cell: a constant, or a value times a factor
+#[derive(Copy, Clone)]
+enum Addend<'a> {
+    Const(isize),
+    Value(&'a str, isize),
+}
+
+struct Emitter<'a> {
+    config: &'a Config,
+    /// The integer type of a cell
+    cell: String,
+    out: String,
+    /// Counters for naming values and blocks in `main`
+    temps: usize,
+    blocks: usize,
+    /// The number of the global holding each span, by its text
+    spans: HashMap<String, usize>,
+}
+
+impl Emitter<'_> {
+    /// Writes everything up to the body of `main`: the tape, the messages and the helpers the body
+    /// calls
+    fn prelude(&mut self) -> Result<()> {
+        let config = self.config;
+        let cell = &self.cell;
+
+        let (len, start) = match config.tape {
+            TapeMode::Fixed => (config.tape_len, 0),
+            TapeMode::Infinite => (config.max_tape_len, config.max_tape_len / 2),
+        };
+
+        let max = config.cell_width.max();
+
+        writeln!(
+            self.out,
+            r#"; Generated by rustfuck
+
+@tape = internal global [{len} x {cell}] zeroinitializer
+
+{}
+{}
+{}
+{}
+{}
+{}
+{}
+{}
+{}
+
+declare i32 @getchar()
+declare i32 @putchar(i32)
+declare i32 @fflush(ptr)
+declare ptr @fdopen(i32, ptr)
+declare i32 @fprintf(ptr, ptr, ...)
+declare void @exit(i32) noreturn
+
+; Shows what was output so far, then opens standard error to report an error on. C libraries each
+; name their `stderr` differently, so it is opened from its file descriptor instead. Exits if that
+; fails
+define internal ptr @open_stderr() cold {{
+  %flushed = call i32 @fflush(ptr null)
+  %stderr = call ptr @fdopen(i32 2, ptr @write_mode)
+  %failed = icmp eq ptr %stderr, null
+  br i1 %failed, label %failed.block, label %opened
+
+failed.block:
+  call void @exit(i32 1)
+  unreachable
+
+opened:
+  ret ptr %stderr
+}}
+
+; Reports an error and exits
+define internal void @fail(ptr %message, ptr %span) cold noreturn {{
+  %stderr = call ptr @open_stderr()
+  %printed = call i32 (ptr, ptr, ...) @fprintf(ptr %stderr, ptr @error, ptr %message, ptr %span)
+  call void @exit(i32 1)
+  unreachable
+}}
+
+; The index of the cell `offset` away from the pointer at `p`
+define internal i64 @at(i64 %p, i64 %offset, ptr %span) {{
+  %i = add i64 %p, %offset"#,
+            string("error", "Error: %s%s\n"),
+            string(
+                "off_tape",
+                &format!(
+                    "Error: Tried to move the pointer to cell %lld, outside of the tape (0..{len}), at %s\n"
+                )
+            ),
+            string(
+                "too_long",
+                &format!("The tape would need more than the maximum of {len} cells, at ")
+            ),
+            string("overflowed", "Cell overflowed at "),
+            string("ran_out", "Ran out of input at "),
+            string("io_failed", "I/O failed at "),
+            string("flush_failed", "Flushing output failed"),
+            string("empty", ""),
+            string("write_mode", "w"),
+        )?;
+
+        match (config.tape, config.pointer_overflow) {
+            (TapeMode::Fixed, Overflow::Wrap) => writeln!(
+                self.out,
+                r#"  %rem = srem i64 %i, {len}
+  %negative = icmp slt i64 %rem, 0
+  %wrapped = add i64 %rem, {len}
+  %index = select i1 %negative, i64 %wrapped, i64 %rem
+  ret i64 %index"#
+            )?,
+            (TapeMode::Fixed, Overflow::Saturate) => writeln!(
+                self.out,
+                r#"  %below = icmp slt i64 %i, 0
+  %low = select i1 %below, i64 0, i64 %i
+  %above = icmp sgt i64 %low, {last}
+  %index = select i1 %above, i64 {last}, i64 %low
+  ret i64 %index"#,
+                last = len - 1
+            )?,
+            (tape, _) => {
+                // An index below 0 is far past the end when compared unsigned
+                writeln!(
+                    self.out,
+                    r#"  %outside = icmp uge i64 %i, {len}
+  br i1 %outside, label %outside.block, label %inside
+
+inside:
+  ret i64 %i
+
+outside.block:"#
+                )?;
+
+                match tape {
+                    TapeMode::Infinite => {
+                        writeln!(self.out, "  call void @fail(ptr @too_long, ptr %span)")?
+                    }
+                    TapeMode::Fixed => writeln!(
+                        self.out,
+                        r#"  %stderr = call ptr @open_stderr()
+  %printed = call i32 (ptr, ptr, ...) @fprintf(ptr %stderr, ptr @off_tape, i64 %i, ptr %span)
+  call void @exit(i32 1)"#
+                    )?,
+                }
+
+                writeln!(self.out, "  unreachable")?;
+            }
+        }
+
+        writeln!(self.out, "}}")?;
+
+        if config.cell_overflow != Overflow::Wrap {
+            // Saturating clamps the sum in its own block, so needs a phi to pick which to store
+            let (overflowed, phi, sum) = match config.cell_overflow {
+                Overflow::Trap => (
+                    "  call void @fail(ptr @overflowed, ptr %span)\n  unreachable".to_string(),
+                    "",
+                    "%value",
+                ),
+                _ => (
+                    format!(
+                        r#"  %negative = icmp slt i64 %value, 0
+  %clamped = select i1 %negative, i64 0, i64 {max}
+  br label %store"#
+                    ),
+                    "  %result = phi i64 [ %value, %entry ], [ %clamped, %overflowed.block ]\n",
+                    "%result",
+                ),
+            };
+
+            writeln!(
+                self.out,
+                r#"
+; Adds `delta` to the cell at `cell`, when cells don't wrap
+define internal void @add(ptr %cell, i64 %delta, ptr %span) {{
+entry:
+  %old = load {cell}, ptr %cell
+  %wide = zext {cell} %old to i64
+  %value = add i64 %wide, %delta
+  %over = icmp ugt i64 %value, {max}
+  br i1 %over, label %overflowed.block, label %store
+
+overflowed.block:
+{overflowed}
+
+store:
+{phi}  %new = trunc i64 {sum} to {cell}
+  store {cell} %new, ptr %cell
+  ret void
+}}"#
+            )?;
+        }
+
+        let eof = match config.eof {
+            EofPolicy::Unchanged => "  ret void".to_string(),
+            EofPolicy::Zero => format!("  store {cell} 0, ptr %cell\n  ret void"),
+            EofPolicy::MinusOne => format!("  store {cell} -1, ptr %cell\n  ret void"),
+            EofPolicy::Error => "  call void @fail(ptr @ran_out, ptr %span)\n  unreachable".into(),
+        };
+
+        // `getchar` and `putchar` deal in `int`s, which only 32-bit cells already are
+        let (narrow, read, widen, byte) = match config.cell_width.bits() {
+            32 => (String::new(), "%c", String::new(), "%value"),
+            _ => (
+                format!("  %narrow = trunc i32 %c to {cell}\n"),
+                "%narrow",
+                format!("  %byte = zext {cell} %value to i32\n"),
+                "%byte",
+            ),
+        };
+
+        writeln!(
+            self.out,
+            r#"
+; Reads a byte into a cell, flushing any output first
+define internal void @input(ptr %cell, ptr %span) {{
+  %flushed = call i32 @fflush(ptr null)
+  %flush_failed = icmp ne i32 %flushed, 0
+  br i1 %flush_failed, label %failed, label %read
+
+read:
+  %c = call i32 @getchar()
+  %eof = icmp slt i32 %c, 0
+  br i1 %eof, label %eof.block, label %store
+
+store:
+{narrow}  store {cell} {read}, ptr %cell
+  ret void
+
+eof.block:
+{eof}
+
+failed:
+  call void @fail(ptr @io_failed, ptr %span)
+  unreachable
+}}
+
+; Outputs the low byte of a cell
+define internal void @output({cell} %value, ptr %span) {{
+{widen}  %low = and i32 {byte}, 255
+  %written = call i32 @putchar(i32 %low)
+  %failed = icmp slt i32 %written, 0
+  br i1 %failed, label %failed.block, label %done
+
+failed.block:
+  call void @fail(ptr @io_failed, ptr %span)
+  unreachable
+
+done:
+  ret void
+}}
+
+define i32 @main() {{
+entry:
+  %p = alloca i64
+  store i64 {start}, ptr %p"#
+        )?;
+
+        Ok(())
+    }
+
+    /// The global holding `span`, adding it if there isn't one yet
+    fn span(&mut self, span: &str) -> String {
+        let next = self.spans.len();
+        let n = *self.spans.entry(span.to_string()).or_insert(next);
+
+        format!("@span.{n}")
+    }
+
+    /// A fresh name for a value in `main`
+    fn temp(&mut self) -> String {
+        self.temps += 1;
+        format!("%t{}", self.temps)
+    }
+
+    /// Starts a loop over the current cell, returning the names of the block which tests it and
+    /// the block after the loop
+    fn loop_blocks(&mut self) -> Result<(String, String)> {
+        self.blocks += 1;
+        let (test, body, after) = (
+            format!("loop{}", self.blocks),
+            format!("body{}", self.blocks),
+            format!("after{}", self.blocks),
+        );
+
+        writeln!(self.out, "  br label %{test}")?;
+        writeln!(self.out, "{test}:")?;
+        let nonzero = self.current_nonzero()?;
+        writeln!(self.out, "  br i1 {nonzero}, label %{body}, label %{after}")?;
+        writeln!(self.out, "{body}:")?;
+
+        Ok((test, after))
+    }
+
+    /// The index of the cell `offset` away from the pointer, which is always on the tape so only
+    /// needs checking if `offset` is non-zero
+    fn index(&mut self, offset: isize, span: &str) -> Result<String> {
+        let p = self.temp();
+        writeln!(self.out, "  {p} = load i64, ptr %p")?;
+
+        if offset == 0 {
+            return Ok(p);
+        }
+
+        let index = self.temp();
+        writeln!(
+            self.out,
+            "  {index} = call i64 @at(i64 {p}, i64 {offset}, ptr {span})"
+        )?;
+
+        Ok(index)
+    }
+
+    /// A pointer to the cell `offset` away from the pointer
+    fn cell_ptr(&mut self, offset: isize, span: &str) -> Result<String> {
+        let index = self.index(offset, span)?;
+        self.element(&index)
+    }
+
+    /// A pointer to the current cell
+    fn current(&mut self) -> Result<String> {
+        let p = self.temp();
+        writeln!(self.out, "  {p} = load i64, ptr %p")?;
+
+        self.element(&p)
+    }
+
+    /// A pointer to the cell at `index`
+    fn element(&mut self, index: &str) -> Result<String> {
+        let cell = self.temp();
+        let len = match self.config.tape {
+            TapeMode::Fixed => self.config.tape_len,
+            TapeMode::Infinite => self.config.max_tape_len,
+        };
+
+        writeln!(
+            self.out,
+            "  {cell} = getelementptr inbounds [{len} x {}], ptr @tape, i64 0, i64 {index}",
+            self.cell
+        )?;
+
+        Ok(cell)
+    }
+
+    /// The value of the current cell
+    fn load_current(&mut self) -> Result<String> {
+        let cell = self.current()?;
+        let value = self.temp();
+        writeln!(self.out, "  {value} = load {}, ptr {cell}", self.cell)?;
+
+        Ok(value)
+    }
+
+    /// Whether the current cell is non-zero, which is what loops test
+    fn current_nonzero(&mut self) -> Result<String> {
+        let value = self.load_current()?;
+        let nonzero = self.temp();
+        writeln!(self.out, "  {nonzero} = icmp ne {} {value}, 0", self.cell)?;
+
+        Ok(nonzero)
+    }
+
+    fn write_zero(&mut self) -> Result<()> {
+        let cell = self.current()?;
+        writeln!(self.out, "  store {} 0, ptr {cell}", self.cell)?;
+
+        Ok(())
+    }
+
+    /// Adds to the cell at `cell`, applying the cell overflow policy
+    fn add(&mut self, cell: &str, addend: Addend, span: &str) -> Result<()> {
+        if self.config.cell_overflow == Overflow::Wrap {
+            // Arithmetic on the cell type wraps for free
+            let addend = match addend {
+                Addend::Const(delta) => self.constant(delta).to_string(),
+                Addend::Value(value, 1) => value.to_string(),
+                Addend::Value(value, factor) => {
+                    let product = self.temp();
+                    writeln!(
+                        self.out,
+                        "  {product} = mul {} {value}, {}",
+                        self.cell,
+                        self.constant(factor)
+                    )?;
+                    product
+                }
+            };
+
+            let (old, new) = (self.temp(), self.temp());
+            writeln!(self.out, "  {old} = load {}, ptr {cell}", self.cell)?;
+            writeln!(self.out, "  {new} = add {} {old}, {addend}", self.cell)?;
+            writeln!(self.out, "  store {} {new}, ptr {cell}", self.cell)?;
+        } else {
+            let delta = match addend {
+                Addend::Const(delta) => delta.to_string(),
+                Addend::Value(value, factor) => {
+                    let wide = self.temp();
+                    writeln!(self.out, "  {wide} = zext {} {value} to i64", self.cell)?;
+
+                    if factor == 1 {
+                        wide
+                    } else {
+                        let product = self.temp();
+                        writeln!(self.out, "  {product} = mul i64 {wide}, {factor}")?;
+                        product
+                    }
+                }
+            };
+
+            writeln!(
+                self.out,
+                "  call void @add(ptr {cell}, i64 {delta}, ptr {span})"
+            )?;
+        }
+
+        Ok(())
+    }
+
+    /// Adds multiples of the current cell to the `targets` then zeroes it, if it isn't already
+    fn add_current(&mut self, targets: &[(isize, isize)], span: &str) -> Result<()> {
+        self.blocks += 1;
+        let (then, after) = (
+            format!("then{}", self.blocks),
+            format!("after{}", self.blocks),
+        );
+
+        let value = self.load_current()?;
+        let nonzero = self.temp();
+        writeln!(self.out, "  {nonzero} = icmp ne {} {value}, 0", self.cell)?;
+        writeln!(self.out, "  br i1 {nonzero}, label %{then}, label %{after}")?;
+        writeln!(self.out, "{then}:")?;
+
+        for &(offset, factor) in targets {
+            let cell = self.cell_ptr(offset, span)?;
+            self.add(&cell, Addend::Value(&value, factor), span)?;
+        }
+
+        self.write_zero()?;
+        writeln!(self.out, "  br label %{after}")?;
+        writeln!(self.out, "{after}:")?;
+
+        Ok(())
+    }
+
+    /// `value` as a constant of the cell type, which LLVM wants in range of the signed type
+    fn constant(&self, value: isize) -> i64 {
+        let shift = 64 - self.config.cell_width.bits();
+        ((value as i64) << shift) >> shift
+    }
+}
+
+/// A constant global holding `text` as a C string
+fn string(name: &str, text: &str) -> String {
+    let mut escaped = String::new();
+
+    for byte in text.bytes() {
+        match byte {
+            b' '..=b'~' if byte != b'"' && byte != b'\\' => escaped.push(byte as char),
+            _ => escaped.push_str(&format!("\\{byte:02X}")),
+        }
+    }
+
+    format!(
+        "@{name} = private unnamed_addr constant [{} x i8] c\"{escaped}\\00\"",
+        text.len() + 1
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ir::Span;
+
+    #[test]
+    fn reports_unbalanced_loops() {
+        // `EmitKind::emit` only translates balanced programs, but LIR can be built by hand
+        let open = Span {
+            start: 3,
+            end: 4,
+            line: 1,
+            column: 4,
+        };
+        let programs = [
+            vec![Spanned::new(LirOp::BrFor, open)],
+            vec![Spanned::new(LirOp::BrBack, open)],
+            vec![
+                Spanned::new(LirOp::BrFor, Span::default()),
+                Spanned::new(LirOp::BrBack, Span::default()),
+                Spanned::new(LirOp::BrFor, open),
+            ],
+        ];
+
+        for program in programs {
+            let err = emit(&program, &Config::default()).unwrap_err();
+
+            assert!(matches!(
+                err.downcast_ref(),
+                Some(&RuntimeError::UnmatchedBracket { span }) if span == open
+            ));
+        }
+    }
+}
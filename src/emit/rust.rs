@@ -0,0 +1,297 @@
+use std::fmt::Write;
+
+use anyhow::Result;
+
+use crate::config::{CellWidth, Config, EofPolicy, Overflow, TapeMode};
+use crate::ir::Spanned;
+use crate::lir::LirOp;
+
+/// Translates `program` into a Rust file holding a single function, `run`, which is meant to be
+/// `include!`d into another crate, usually from a build script
+///
+/// The tape is a boxed array indexed by `p`, and every access away from the pointer goes through
+/// `at`, which applies the pointer overflow policy. Errors are returned as [`std::io::Error`]s
+/// mentioning the source location of the op which failed
+pub(super) fn emit(program: &[Spanned<LirOp>], config: &Config) -> Result<Vec<u8>> {
+    let mut out = String::new();
+
+    prelude(&mut out, config)?;
+
+    let wraps = config.cell_overflow == Overflow::Wrap;
+    let mut depth = 1;
+
+    for Spanned { op, span } in program {
+        let indent = "    ".repeat(depth);
+        let span = format!("\"{span}\"");
+
+        match *op {
+            LirOp::Move(delta) => writeln!(out, "{indent}p = at(p, {delta}, {span})?;")?,
+            LirOp::OffsetModify(delta, offset) if wraps => writeln!(
+                out,
+                "{indent}wrapping_add(&mut tape[{}], {});",
+                index(offset, &span),
+                config.cell_width.wrap(delta as u32)
+            )?,
+            LirOp::OffsetModify(delta, offset) => writeln!(
+                out,
+                "{indent}add(&mut tape[{}], {delta}, {span})?;",
+                index(offset, &span)
+            )?,
+            LirOp::WriteZero => writeln!(out, "{indent}tape[p] = 0;")?,
+            LirOp::Hop(delta) => {
+                writeln!(out, "{indent}while tape[p] != 0 {{")?;
+                writeln!(out, "{indent}    p = at(p, {delta}, {span})?;")?;
+                writeln!(out, "{indent}}}")?;
+            }
+            LirOp::MoveCell(delta) => {
+                writeln!(out, "{indent}if tape[p] != 0 {{")?;
+                writeln!(out, "{indent}    let v = tape[p];")?;
+
+                if wraps {
+                    writeln!(
+                        out,
+                        "{indent}    wrapping_add(&mut tape[{}], v);",
+                        index(delta, &span)
+                    )?;
+                } else {
+                    writeln!(
+                        out,
+                        "{indent}    add(&mut tape[{}], v as i64, {span})?;",
+                        index(delta, &span)
+                    )?;
+                }
+
+                writeln!(out, "{indent}    tape[p] = 0;")?;
+                writeln!(out, "{indent}}}")?;
+            }
+            LirOp::MulAdd(targets) => {
+                writeln!(out, "{indent}if tape[p] != 0 {{")?;
+                writeln!(out, "{indent}    let v = tape[p];")?;
+
+                for &(offset, factor) in targets {
+                    if wraps {
+                        // Only the low bits of the product end up in the cell
+                        writeln!(
+                            out,
+                            "{indent}    wrapping_add(&mut tape[{}], v.wrapping_mul({}));",
+                            index(offset, &span),
+                            config.cell_width.wrap(factor as u32)
+                        )?;
+                    } else {
+                        writeln!(
+                            out,
+                            "{indent}    add(&mut tape[{}], v as i64 * {factor}, {span})?;",
+                            index(offset, &span)
+                        )?;
+                    }
+                }
+
+                writeln!(out, "{indent}    tape[p] = 0;")?;
+                writeln!(out, "{indent}}}")?;
+            }
+            LirOp::In => writeln!(out, "{indent}read(input, output, &mut tape[p], {span})?;")?,
+            LirOp::Out => writeln!(
+                out,
+                "{indent}output.write_all(&[tape[p] as u8]).map_err(|err| failed(err, {span}))?;"
+            )?,
+            LirOp::BrFor => {
+                writeln!(out, "{indent}while tape[p] != 0 {{")?;
+                depth += 1;
+            }
+            LirOp::BrBack => {
+                depth -= 1;
+                writeln!(out, "{}}}", "    ".repeat(depth))?;
+            }
+            LirOp::Meta(comment) => writeln!(out, "{indent}// {}", comment.replace('\n', " "))?,
+        }
+    }
+
+    write!(
+        out,
+        r#"
+    output
+        .flush()
+        .map_err(|err| Error::new(err.kind(), format!("Flushing output failed: {{err}}")))
+}}
+"#
+    )?;
+
+    Ok(out.into_bytes())
+}
+
+/// Writes everything up to the body of `run`: its signature, the helpers the body calls and the
+/// tape
+fn prelude(out: &mut String, config: &Config) -> Result<()> {
+    let (len, start) = match config.tape {
+        TapeMode::Fixed => (config.tape_len, 0),
+        TapeMode::Infinite => (config.max_tape_len, config.max_tape_len / 2),
+    };
+
+    let cell = match config.cell_width {
+        CellWidth::U8 => "u8",
+        CellWidth::U16 => "u16",
+        CellWidth::U32 => "u32",
+    };
+
+    write!(
+        out,
+        r#"// Generated by rustfuck
+
+/// Runs the program on a fresh tape, reading `,` from `input` and writing `.` to `output`
+///
+/// Fails if the program does, mentioning where in the source it failed
+#[allow(dead_code, unused_assignments, unused_mut, unused_variables, clippy::all)]
+pub fn run(
+    input: &mut impl std::io::Read,
+    output: &mut impl std::io::Write,
+) -> std::io::Result<()> {{
+    use std::io::{{Error, ErrorKind, Read, Result, Write}};
+
+    type Cell = {cell};
+
+    const TAPE_LEN: usize = {len};
+
+    /// The index of the cell `offset` away from the pointer at `p`
+    #[inline]
+    fn at(p: usize, offset: isize, span: &str) -> Result<usize> {{
+        let i = p as isize + offset;
+
+"#
+    )?;
+
+    let off_tape = match (config.tape, config.pointer_overflow) {
+        (TapeMode::Infinite, _) => {
+            Some(r#""The tape would need more than the maximum of {TAPE_LEN} cells, at {span}""#)
+        }
+        (TapeMode::Fixed, Overflow::Wrap) => {
+            writeln!(out, "        Ok(i.rem_euclid(TAPE_LEN as isize) as usize)")?;
+            None
+        }
+        (TapeMode::Fixed, Overflow::Saturate) => {
+            writeln!(
+                out,
+                "        Ok(i.clamp(0, TAPE_LEN as isize - 1) as usize)"
+            )?;
+            None
+        }
+        (TapeMode::Fixed, Overflow::Trap) => Some(
+            r#""Tried to move the pointer to cell {i}, outside of the tape (0..{TAPE_LEN}), at {span}""#,
+        ),
+    };
+
+    if off_tape.is_some() {
+        writeln!(
+            out,
+            r#"        if i < 0 || i >= TAPE_LEN as isize {{
+            return Err(off_tape(i, span));
+        }}
+
+        Ok(i as usize)"#
+        )?;
+    }
+
+    let off_tape = match off_tape {
+        Some(message) => format!(
+            r#"    /// Builds the error for moving the pointer off the tape
+    #[cold]
+    #[inline(never)]
+    fn off_tape(i: isize, span: &str) -> Error {{
+        Error::new(ErrorKind::Other, format!({message}))
+    }}
+
+"#
+        ),
+        None => String::new(),
+    };
+
+    let overflowed = match config.cell_overflow {
+        Overflow::Trap => "return Err(overflowed(span));",
+        _ => "value = value.clamp(0, Cell::MAX as i64);",
+    };
+
+    let eof = match config.eof {
+        EofPolicy::Unchanged => "{}",
+        EofPolicy::Zero => "*cell = 0,",
+        EofPolicy::MinusOne => "*cell = Cell::MAX,",
+        EofPolicy::Error => {
+            r#"{
+                return Err(Error::new(ErrorKind::UnexpectedEof, format!("Ran out of input at {span}")));
+            }"#
+        }
+    };
+
+    write!(
+        out,
+        r#"    }}
+
+    #[inline]
+    fn wrapping_add(cell: &mut Cell, delta: Cell) {{
+        *cell = cell.wrapping_add(delta);
+    }}
+
+    /// Adds `delta` to `cell`, when cells don't wrap
+    #[inline]
+    fn add(cell: &mut Cell, delta: i64, span: &str) -> Result<()> {{
+        let mut value = *cell as i64 + delta;
+
+        if value < 0 || value > Cell::MAX as i64 {{
+            {overflowed}
+        }}
+
+        *cell = value as Cell;
+        Ok(())
+    }}
+
+    /// Reads a byte into a cell, flushing any output first
+    fn read(
+        input: &mut impl Read,
+        output: &mut impl Write,
+        cell: &mut Cell,
+        span: &str,
+    ) -> Result<()> {{
+        output.flush().map_err(|err| failed(err, span))?;
+
+        let mut byte = [0];
+        match input.read_exact(&mut byte) {{
+            Ok(()) => *cell = byte[0] as Cell,
+            Err(err) if err.kind() == ErrorKind::UnexpectedEof => {eof}
+            Err(err) => return Err(failed(err, span)),
+        }}
+
+        Ok(())
+    }}
+
+    /// Adds where the program was to an I/O error
+    #[cold]
+    fn failed(err: Error, span: &str) -> Error {{
+        Error::new(err.kind(), format!("I/O failed at {{span}}: {{err}}"))
+    }}
+
+    /// Errors are rare, so building them is kept out of line to keep the checks which might
+    /// need them small
+    #[cold]
+    #[inline(never)]
+    fn overflowed(span: &str) -> Error {{
+        Error::new(ErrorKind::Other, format!("Cell overflowed at {{span}}"))
+    }}
+
+{off_tape}    // An array lets the compiler see that cells `at` has checked are on the tape. `TryInto` is
+    // spelled out as it is only in the prelude from edition 2021
+    let mut tape: Box<[Cell; TAPE_LEN]> =
+        std::convert::TryInto::try_into(vec![0; TAPE_LEN].into_boxed_slice()).unwrap();
+    let mut p: usize = {start};
+
+"#
+    )?;
+
+    Ok(())
+}
+
+/// The index of the cell `offset` away from the pointer, which is always on the tape so only
+/// needs checking if `offset` is non-zero
+fn index(offset: isize, span: &str) -> String {
+    match offset {
+        0 => "p".into(),
+        offset => format!("at(p, {offset}, {span})?"),
+    }
+}
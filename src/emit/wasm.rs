@@ -0,0 +1,551 @@
+use std::collections::HashMap;
+
+use anyhow::{bail, Context, Result};
+
+use crate::config::{Config, EofPolicy, Overflow, TapeMode};
+use crate::ir::{Span, Spanned};
+use crate::lir::LirOp;
+
+/// Memories are sized in pages of 64KiB, and can have at most 4GiB
+const PAGE_LEN: u64 = 0x1_0000;
+const MAX_PAGES: u64 = 0x1_0000;
+
+/// Section ids, in the order sections have to appear
+const TYPE_SECTION: u8 = 1;
+const IMPORT_SECTION: u8 = 2;
+const FUNCTION_SECTION: u8 = 3;
+const MEMORY_SECTION: u8 = 5;
+const EXPORT_SECTION: u8 = 7;
+const CODE_SECTION: u8 = 10;
+const DATA_SECTION: u8 = 11;
+
+/// Value types, and the block type of blocks which leave nothing on the stack
+const I32: u8 = 0x7f;
+const I64: u8 = 0x7e;
+const EMPTY: u8 = 0x40;
+
+/// Indices of the imported functions, which come before `run`
+const GETCHAR: u8 = 0;
+const PUTCHAR: u8 = 1;
+const RUN: u8 = 2;
+
+/// Locals of `run`: the pointer, scratch space for a cell's index or address and its value, and
+/// a 64-bit sum for arithmetic which mustn't wrap
+const P: u8 = 0;
+const INDEX: u8 = 1;
+const VALUE: u8 = 2;
+const WIDE: u8 = 3;
+
+/// The opcodes we use
+const LOOP: u8 = 0x03;
+const IF: u8 = 0x04;
+const ELSE: u8 = 0x05;
+const END: u8 = 0x0b;
+const BR_IF: u8 = 0x0d;
+const RETURN: u8 = 0x0f;
+const CALL: u8 = 0x10;
+const SELECT: u8 = 0x1b;
+const LOCAL_GET: u8 = 0x20;
+const LOCAL_SET: u8 = 0x21;
+const LOCAL_TEE: u8 = 0x22;
+const I32_LOAD: u8 = 0x28;
+const I32_LOAD8_U: u8 = 0x2d;
+const I32_LOAD16_U: u8 = 0x2f;
+const I32_STORE: u8 = 0x36;
+const I32_STORE8: u8 = 0x3a;
+const I32_STORE16: u8 = 0x3b;
+const I32_CONST: u8 = 0x41;
+const I64_CONST: u8 = 0x42;
+const I32_LT_S: u8 = 0x48;
+const I32_GT_S: u8 = 0x4a;
+const I32_GE_U: u8 = 0x4f;
+const I64_LT_S: u8 = 0x53;
+const I64_GT_U: u8 = 0x56;
+const I32_ADD: u8 = 0x6a;
+const I32_MUL: u8 = 0x6c;
+const I32_REM_U: u8 = 0x70;
+const I32_AND: u8 = 0x71;
+const I64_ADD: u8 = 0x7c;
+const I64_MUL: u8 = 0x7e;
+const I32_WRAP_I64: u8 = 0xa7;
+const I64_EXTEND_I32_U: u8 = 0xad;
+
+/// Translates `program` into a WebAssembly module
+///
+/// The module imports `getchar: () -> i32`, which returns a negative number at the end of input,
+/// and `putchar: (i32) -> ()` from `env`, and exports its `memory` and `run: () -> i32`. The tape
+/// is at the start of the memory and `run` starts on a fresh one, so each run needs a new
+/// instance. `run` returns 0 if the program finished, otherwise the address in `memory` of an
+/// error message, as a little endian `u32` length followed by that many bytes of UTF-8, which
+/// says where in the source the program failed
+pub(super) fn emit(program: &[Spanned<LirOp>], config: &Config) -> Result<Vec<u8>> {
+    let mut function = Function::new(config)?;
+
+    for Spanned { op, span } in program {
+        function.op(*op, *span)?;
+    }
+
+    function.i32_const(0);
+    function.code.push(END);
+
+    let Function { code, messages, .. } = function;
+
+    let pages = (messages.base + messages.data.len() as u64).div_ceil(PAGE_LEN);
+    if pages > MAX_PAGES {
+        bail!("The tape doesn't fit in a WebAssembly memory");
+    }
+
+    Ok(module(&code, pages, &messages))
+}
+
+/// The body of `run`, along with the error messages it can return
+struct Function<'a> {
+    config: &'a Config,
+    code: Vec<u8>,
+    messages: Messages,
+    /// How many cells the tape has, and how many bytes each takes up
+    len: i32,
+    cell_bytes: i32,
+}
+
+impl<'a> Function<'a> {
+    fn new(config: &'a Config) -> Result<Function<'a>> {
+        let (len, start) = match config.tape {
+            TapeMode::Fixed => (config.tape_len, 0),
+            TapeMode::Infinite => (config.max_tape_len, config.max_tape_len / 2),
+        };
+
+        let cell_bytes = config.cell_width.bits() as i32 / 8;
+
+        // Keeping every address in an `i32` means they never need to be checked for overflow
+        let len = i32::try_from(len)
+            .ok()
+            .filter(|len| len.checked_mul(cell_bytes).is_some())
+            .context("The tape doesn't fit in a WebAssembly memory")?;
+
+        let mut function = Function {
+            config,
+            code: Vec::new(),
+            messages: Messages {
+                base: (len * cell_bytes) as u64,
+                data: Vec::new(),
+                addresses: HashMap::new(),
+            },
+            len,
+            cell_bytes,
+        };
+
+        // The pointer, and the other locals, start at 0
+        if start != 0 {
+            function.i32_const(start as i32);
+            function.code.extend([LOCAL_SET, P]);
+        }
+
+        Ok(function)
+    }
+
+    fn op(&mut self, op: LirOp, span: Span) -> Result<()> {
+        match op {
+            LirOp::Move(delta) => {
+                self.index(delta, span)?;
+                self.code.extend([LOCAL_SET, P]);
+            }
+            LirOp::OffsetModify(delta, offset) => self.add(offset, Addend::Const(delta), span)?,
+            LirOp::WriteZero => {
+                self.address(0, span)?;
+                self.i32_const(0);
+                self.store();
+            }
+            LirOp::Hop(delta) => {
+                self.load(0, span)?;
+                self.code.extend([IF, EMPTY, LOOP, EMPTY]);
+                self.index(delta, span)?;
+                self.code.extend([LOCAL_SET, P]);
+                self.load(0, span)?;
+                self.code.extend([BR_IF, 0, END, END]);
+            }
+            LirOp::MoveCell(delta) => self.add_current(&[(delta, 1)], span)?,
+            LirOp::MulAdd(targets) => self.add_current(targets, span)?,
+            LirOp::In => self.input(span)?,
+            LirOp::Out => {
+                self.load(0, span)?;
+                self.i32_const(0xff);
+                self.code.extend([I32_AND, CALL, PUTCHAR]);
+            }
+            LirOp::BrFor => {
+                self.load(0, span)?;
+                self.code.extend([IF, EMPTY, LOOP, EMPTY]);
+            }
+            LirOp::BrBack => {
+                self.load(0, span)?;
+                self.code.extend([BR_IF, 0, END, END]);
+            }
+            LirOp::Meta(_) => {}
+        }
+
+        Ok(())
+    }
+
+    /// Pushes the index of the cell `offset` away from the pointer, applying the pointer overflow
+    /// policy, or returns an error if it has to
+    fn index(&mut self, offset: isize, span: Span) -> Result<()> {
+        self.code.extend([LOCAL_GET, P]);
+
+        // The pointer is always on the tape
+        if offset == 0 {
+            return Ok(());
+        }
+
+        let len = self.len as isize;
+
+        match (self.config.tape, self.config.pointer_overflow) {
+            (TapeMode::Fixed, Overflow::Wrap) => {
+                // Reducing the offset first keeps the sum positive
+                self.i32_const(offset.rem_euclid(len) as i32);
+                self.code.push(I32_ADD);
+                self.i32_const(self.len);
+                self.code.push(I32_REM_U);
+            }
+            (TapeMode::Fixed, Overflow::Saturate) => {
+                // Anything further away saturates the same way, and can't overflow
+                let offset = offset.clamp(-len, len) as i32;
+                let (bound, past) = if offset > 0 {
+                    (self.len - 1, I32_GT_S)
+                } else {
+                    (0, I32_LT_S)
+                };
+
+                self.i32_const(offset);
+                self.code.extend([I32_ADD, LOCAL_SET, INDEX]);
+                self.i32_const(bound);
+                self.code.extend([LOCAL_GET, INDEX, LOCAL_GET, INDEX]);
+                self.i32_const(bound);
+                self.code.extend([past, SELECT]);
+            }
+            (tape, _) => {
+                let offset = i32::try_from(offset).context("Pointer offset is too large")?;
+
+                // An index below 0 wraps around to one far past the end
+                self.i32_const(offset);
+                self.code.extend([I32_ADD, LOCAL_TEE, INDEX]);
+                self.i32_const(self.len);
+                self.code.extend([I32_GE_U, IF, EMPTY]);
+
+                let message = match tape {
+                    TapeMode::Infinite => format!(
+                        "The tape would need more than the maximum of {} cells, at {span}",
+                        self.len
+                    ),
+                    TapeMode::Fixed => format!(
+                        "Tried to move the pointer outside of the tape (0..{}), at {span}",
+                        self.len
+                    ),
+                };
+
+                self.fail(message);
+                self.code.extend([END, LOCAL_GET, INDEX]);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Pushes the address of the cell `offset` away from the pointer
+    fn address(&mut self, offset: isize, span: Span) -> Result<()> {
+        self.index(offset, span)?;
+
+        if self.cell_bytes != 1 {
+            self.i32_const(self.cell_bytes);
+            self.code.push(I32_MUL);
+        }
+
+        Ok(())
+    }
+
+    /// Pushes the value of the cell `offset` away from the pointer
+    fn load(&mut self, offset: isize, span: Span) -> Result<()> {
+        self.address(offset, span)?;
+        self.memory_op(self.load_op());
+
+        Ok(())
+    }
+
+    /// Loads zero extend cells narrower than an `i32`
+    fn load_op(&self) -> u8 {
+        match self.cell_bytes {
+            1 => I32_LOAD8_U,
+            2 => I32_LOAD16_U,
+            _ => I32_LOAD,
+        }
+    }
+
+    /// Stores a value into the cell at an address, both from the stack, keeping just the low bits
+    /// of the value
+    fn store(&mut self) {
+        let store = match self.cell_bytes {
+            1 => I32_STORE8,
+            2 => I32_STORE16,
+            _ => I32_STORE,
+        };
+
+        self.memory_op(store);
+    }
+
+    /// Loads and stores give the alignment of the address as a power of two, and an offset
+    fn memory_op(&mut self, op: u8) {
+        let align = self.cell_bytes.trailing_zeros() as u8;
+        self.code.extend([op, align, 0]);
+    }
+
+    /// Adds to the cell `offset` away from the pointer, applying the cell overflow policy
+    fn add(&mut self, offset: isize, addend: Addend, span: Span) -> Result<()> {
+        // The address stays on the stack for the store at the end
+        self.address(offset, span)?;
+        self.code.extend([LOCAL_TEE, INDEX, LOCAL_GET, INDEX]);
+        self.memory_op(self.load_op());
+
+        if self.config.cell_overflow == Overflow::Wrap {
+            // Stores only keep the low bits, so the sum wraps for free
+            match addend {
+                Addend::Const(delta) => {
+                    self.i32_const(self.config.cell_width.wrap(delta as u32) as i32)
+                }
+                Addend::Value(1) => self.code.extend([LOCAL_GET, VALUE]),
+                Addend::Value(factor) => {
+                    self.code.extend([LOCAL_GET, VALUE]);
+                    self.i32_const(self.config.cell_width.wrap(factor as u32) as i32);
+                    self.code.push(I32_MUL);
+                }
+            }
+
+            self.code.push(I32_ADD);
+        } else {
+            self.code.push(I64_EXTEND_I32_U);
+
+            match addend {
+                Addend::Const(delta) => self.i64_const(delta as i64),
+                Addend::Value(1) => self.code.extend([LOCAL_GET, VALUE, I64_EXTEND_I32_U]),
+                Addend::Value(factor) => {
+                    self.code.extend([LOCAL_GET, VALUE, I64_EXTEND_I32_U]);
+                    self.i64_const(factor as i64);
+                    self.code.push(I64_MUL);
+                }
+            }
+
+            // Anything below 0 is far above the maximum when compared unsigned
+            self.code.extend([I64_ADD, LOCAL_TEE, WIDE]);
+            self.i64_const(self.config.cell_width.max().into());
+            self.code.extend([I64_GT_U, IF, EMPTY]);
+
+            if self.config.cell_overflow == Overflow::Trap {
+                self.fail(format!("Cell overflowed at {span}"));
+            } else {
+                self.i64_const(0);
+                self.i64_const(self.config.cell_width.max().into());
+                self.code.extend([LOCAL_GET, WIDE]);
+                self.i64_const(0);
+                self.code.extend([I64_LT_S, SELECT, LOCAL_SET, WIDE]);
+            }
+
+            self.code.extend([END, LOCAL_GET, WIDE, I32_WRAP_I64]);
+        }
+
+        self.store();
+
+        Ok(())
+    }
+
+    /// Adds multiples of the current cell to the `targets` then zeroes it, if it isn't already
+    fn add_current(&mut self, targets: &[(isize, isize)], span: Span) -> Result<()> {
+        self.load(0, span)?;
+        self.code.extend([LOCAL_TEE, VALUE, IF, EMPTY]);
+
+        for &(offset, factor) in targets {
+            self.add(offset, Addend::Value(factor), span)?;
+        }
+
+        self.address(0, span)?;
+        self.i32_const(0);
+        self.store();
+        self.code.push(END);
+
+        Ok(())
+    }
+
+    /// Reads a byte into the current cell, applying the EOF policy at the end of input
+    fn input(&mut self, span: Span) -> Result<()> {
+        self.code.extend([CALL, GETCHAR, LOCAL_TEE, VALUE]);
+        self.i32_const(0);
+        self.code.extend([I32_LT_S, IF, EMPTY]);
+
+        match self.config.eof {
+            EofPolicy::Unchanged => {}
+            EofPolicy::Zero => {
+                self.address(0, span)?;
+                self.i32_const(0);
+                self.store();
+            }
+            EofPolicy::MinusOne => {
+                self.address(0, span)?;
+                self.i32_const(self.config.cell_width.max() as i32);
+                self.store();
+            }
+            EofPolicy::Error => self.fail(format!("Ran out of input at {span}")),
+        }
+
+        self.code.push(ELSE);
+        self.address(0, span)?;
+        self.code.extend([LOCAL_GET, VALUE]);
+        self.store();
+        self.code.push(END);
+
+        Ok(())
+    }
+
+    /// Returns the address of `message` from `run`
+    fn fail(&mut self, message: String) {
+        let address = self.messages.add(message);
+        self.i32_const(address as i32);
+        self.code.push(RETURN);
+    }
+
+    fn i32_const(&mut self, value: i32) {
+        self.code.push(I32_CONST);
+        signed(&mut self.code, value.into());
+    }
+
+    fn i64_const(&mut self, value: i64) {
+        self.code.push(I64_CONST);
+        signed(&mut self.code, value);
+    }
+}
+
+/// What [`Function::add`] adds to a cell: a constant, or `VALUE` times a factor
+#[derive(Copy, Clone)]
+enum Addend {
+    Const(isize),
+    Value(isize),
+}
+
+/// The error messages `run` can return, which are kept in the memory after the tape
+struct Messages {
+    base: u64,
+    data: Vec<u8>,
+    addresses: HashMap<String, u64>,
+}
+
+impl Messages {
+    /// The address of `message`, adding it if it isn't already there
+    fn add(&mut self, message: String) -> u64 {
+        if let Some(&address) = self.addresses.get(&message) {
+            return address;
+        }
+
+        let address = self.base + self.data.len() as u64;
+        self.data
+            .extend_from_slice(&(message.len() as u32).to_le_bytes());
+        self.data.extend_from_slice(message.as_bytes());
+        self.addresses.insert(message, address);
+
+        address
+    }
+}
+
+/// Builds the module around the body of `run`
+fn module(code: &[u8], pages: u64, messages: &Messages) -> Vec<u8> {
+    let mut module = b"\0asm".to_vec();
+    module.extend_from_slice(&1u32.to_le_bytes());
+
+    // `() -> i32` for `getchar` and `run`, then `(i32) -> ()` for `putchar`
+    section(
+        &mut module,
+        TYPE_SECTION,
+        &[2, 0x60, 0, 1, I32, 0x60, 1, I32, 0],
+    );
+
+    let mut imports = vec![2];
+    for (name, kind) in [("getchar", 0), ("putchar", 1)] {
+        name_bytes(&mut imports, "env");
+        name_bytes(&mut imports, name);
+        imports.extend([0, kind]);
+    }
+    section(&mut module, IMPORT_SECTION, &imports);
+
+    section(&mut module, FUNCTION_SECTION, &[1, 0]);
+
+    // A minimum size, and no maximum
+    let mut memory = vec![1, 0];
+    unsigned(&mut memory, pages);
+    section(&mut module, MEMORY_SECTION, &memory);
+
+    let mut exports = vec![2];
+    name_bytes(&mut exports, "run");
+    exports.extend([0, RUN]);
+    name_bytes(&mut exports, "memory");
+    exports.extend([2, 0]);
+    section(&mut module, EXPORT_SECTION, &exports);
+
+    // Three `i32` locals, then one `i64`
+    let mut body = vec![2, 3, I32, 1, I64];
+    body.extend_from_slice(code);
+    let mut bodies = vec![1];
+    unsigned(&mut bodies, body.len() as u64);
+    bodies.extend(body);
+    section(&mut module, CODE_SECTION, &bodies);
+
+    if !messages.data.is_empty() {
+        // One segment, for memory 0, loaded at the address after the tape
+        let mut data = vec![1, 0, I32_CONST];
+        signed(&mut data, messages.base as i64);
+        data.push(END);
+        unsigned(&mut data, messages.data.len() as u64);
+        data.extend_from_slice(&messages.data);
+        section(&mut module, DATA_SECTION, &data);
+    }
+
+    module
+}
+
+/// Appends a section, which is prefixed with its id and length
+fn section(module: &mut Vec<u8>, id: u8, contents: &[u8]) {
+    module.push(id);
+    unsigned(module, contents.len() as u64);
+    module.extend_from_slice(contents);
+}
+
+/// Appends a name, as its length then its bytes
+fn name_bytes(out: &mut Vec<u8>, name: &str) {
+    unsigned(out, name.len() as u64);
+    out.extend_from_slice(name.as_bytes());
+}
+
+/// Appends `value` in unsigned LEB128
+fn unsigned(out: &mut Vec<u8>, mut value: u64) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+
+        if value == 0 {
+            out.push(byte);
+            return;
+        }
+
+        out.push(byte | 0x80);
+    }
+}
+
+/// Appends `value` in signed LEB128
+fn signed(out: &mut Vec<u8>, mut value: i64) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+
+        // Done once the rest is all sign bits, and the sign bit of this byte agrees
+        if (value == 0 && byte & 0x40 == 0) || (value == -1 && byte & 0x40 != 0) {
+            out.push(byte);
+            return;
+        }
+
+        out.push(byte | 0x80);
+    }
+}
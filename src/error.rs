@@ -0,0 +1,120 @@
+use std::{
+    error::Error,
+    fmt::{self, Display},
+    io,
+};
+
+use crate::ir::Span;
+use crate::limit::LimitExceeded;
+
+/// Why a program stopped part way through
+///
+/// These are all down to the program or its environment rather than a bug in rustfuck, so are
+/// returned from every backend rather than panicking
+#[derive(Debug)]
+pub enum RuntimeError {
+    /// The pointer was moved to, or a cell was accessed at, `cell`, which is off a fixed tape of
+    /// `len` cells and pointer overflow traps
+    PointerOutOfRange { span: Span, cell: isize, len: usize },
+    /// The pointer was already off the tape before the program started
+    StartOutOfRange { cell: usize, len: usize },
+    /// The tape would have to hold `len` cells, more than the `max` it is allowed. A fixed tape
+    /// which is too long fails before the program starts, so has no span
+    TapeTooLarge {
+        span: Option<Span>,
+        len: usize,
+        max: usize,
+    },
+    /// A cell went past 0 or its largest value and cell overflow traps
+    CellOverflow { span: Span },
+    /// Reading input or writing output failed. Flushing output once the program has finished
+    /// isn't down to any one instruction, so has no span
+    Io {
+        span: Option<Span>,
+        source: io::Error,
+    },
+    /// `,` ran out of input and the EOF policy is to fail
+    Eof { span: Span },
+    /// The program ran into one of its limits
+    LimitExceeded(LimitExceeded),
+    /// A `[` or `]` has no partner, which is only possible in a program which didn't come from
+    /// the parser
+    UnmatchedBracket { span: Span },
+}
+
+impl RuntimeError {
+    /// Wraps an I/O error from the instruction at `span`, unless it is the EOF policy asking for
+    /// the program to stop
+    pub fn io(source: io::Error, span: Span) -> Self {
+        if source.kind() == io::ErrorKind::UnexpectedEof {
+            RuntimeError::Eof { span }
+        } else {
+            RuntimeError::Io {
+                span: Some(span),
+                source,
+            }
+        }
+    }
+
+    /// The instruction which failed, if it was down to any one instruction
+    pub fn span(&self) -> Option<Span> {
+        match self {
+            RuntimeError::PointerOutOfRange { span, .. }
+            | RuntimeError::CellOverflow { span }
+            | RuntimeError::Eof { span }
+            | RuntimeError::UnmatchedBracket { span } => Some(*span),
+            RuntimeError::Io { span, .. } | RuntimeError::TapeTooLarge { span, .. } => *span,
+            RuntimeError::LimitExceeded(limit) => Some(limit.span),
+            RuntimeError::StartOutOfRange { .. } => None,
+        }
+    }
+}
+
+impl Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RuntimeError::PointerOutOfRange { span, cell, len } => write!(
+                f,
+                "Tried to move the pointer to cell {cell}, outside of the tape (0..{len}), at {span}"
+            ),
+            RuntimeError::StartOutOfRange { cell, len } => write!(
+                f,
+                "The pointer starts at cell {cell}, outside of the tape (0..{len})"
+            ),
+            RuntimeError::TapeTooLarge { span, len, max } => {
+                write!(
+                    f,
+                    "The tape would need {len} cells, more than the maximum of {max}"
+                )?;
+
+                match span {
+                    Some(span) => write!(f, ", at {span}"),
+                    None => Ok(()),
+                }
+            }
+            RuntimeError::CellOverflow { span } => write!(f, "Cell overflowed at {span}"),
+            RuntimeError::Io {
+                span: Some(span), ..
+            } => write!(f, "I/O failed at {span}"),
+            RuntimeError::Io { span: None, .. } => write!(f, "Flushing output failed"),
+            RuntimeError::Eof { span } => write!(f, "Ran out of input at {span}"),
+            RuntimeError::LimitExceeded(limit) => limit.fmt(f),
+            RuntimeError::UnmatchedBracket { span } => write!(f, "Unmatched bracket at {span}"),
+        }
+    }
+}
+
+impl Error for RuntimeError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            RuntimeError::Io { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+impl From<LimitExceeded> for RuntimeError {
+    fn from(limit: LimitExceeded) -> Self {
+        RuntimeError::LimitExceeded(limit)
+    }
+}
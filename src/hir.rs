@@ -1,16 +1,24 @@
 use std::{
     collections::HashMap,
-    io::{self, Read, Write},
+    io::{Read, Write},
 };
 
 use anyhow::Result;
+use bumpalo::Bump;
 use log::{info, trace};
 use tap::prelude::*;
 
-use crate::{ir::IrLike, state::BrainfuckState};
+use crate::{
+    backend::Backend,
+    config::{Config, Overflow, TapeMode},
+    error::RuntimeError,
+    ir::{gen_branch_table, IrLike, LoopMarkers, Span, Spanned},
+    limit::Budget,
+    state::BrainfuckState,
+};
 
 /// Represents a "real" brainfuck operation before optimisation
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
 pub enum BfOp {
     Inc,
     Dec,
@@ -26,6 +34,9 @@ pub enum BfOp {
 /// Represents operations after the first opt pass
 /// * +- have been collapsed
 /// * >< have been collapsed
+///
+/// Unless overflow wraps (or the tape is infinite), only runs in one direction are collapsed, as
+/// going past the end and coming back has to be noticed
 #[derive(Debug, PartialEq, Eq, Copy, Clone)]
 pub enum HirOp {
     Modify(isize), // add or subtract
@@ -36,6 +47,11 @@ pub enum HirOp {
     BrBack,
 }
 
+impl LoopMarkers for HirOp {
+    const BR_FOR: Self = HirOp::BrFor;
+    const BR_BACK: Self = HirOp::BrBack;
+}
+
 impl IrLike for HirOp {
     fn to_compact(&self) -> String {
         match self {
@@ -64,28 +80,31 @@ impl IrLike for HirOp {
 pub struct HirGen;
 
 impl HirGen {
-    pub fn gen(program: &[BfOp]) -> Vec<HirOp> {
+    pub fn gen(program: &[Spanned<BfOp>], config: &Config) -> Vec<Spanned<HirOp>> {
         info!("Starting HIR gen");
 
-        Self::lower(program).tap(|ir| trace!("Lowered HIR: {}", ir.to_compact()))
+        Self::lower(program, config).tap(|ir| trace!("Lowered HIR: {}", ir.to_compact()))
     }
 
-    fn lower(bf: &[BfOp]) -> Vec<HirOp> {
+    fn lower(bf: &[Spanned<BfOp>], config: &Config) -> Vec<Spanned<HirOp>> {
         let mut result = Vec::new();
 
         let mut pos = 0;
 
         while let Some(bf_op) = bf.get(pos) {
-            let hir_op = match bf_op {
+            let hir_op = match bf_op.op {
                 BfOp::Inc | BfOp::Dec => {
-                    let mod_ops = bf[pos..]
-                        .iter()
-                        .take_while(|op| matches!(op, BfOp::Inc | BfOp::Dec));
+                    let mod_ops = bf[pos..].iter().take_while(|op| match op.op {
+                        BfOp::Inc | BfOp::Dec => {
+                            config.cell_overflow == Overflow::Wrap || op.op == bf_op.op
+                        }
+                        _ => false,
+                    });
 
                     let delta = mod_ops
                         .map(|op| {
                             pos += 1;
-                            if *op == BfOp::Inc {
+                            if op.op == BfOp::Inc {
                                 1
                             } else {
                                 -1
@@ -96,14 +115,19 @@ impl HirGen {
                     HirOp::Modify(delta)
                 }
                 BfOp::MvRight | BfOp::MvLeft => {
-                    let mod_ops = bf[pos..]
-                        .iter()
-                        .take_while(|op| matches!(op, BfOp::MvRight | BfOp::MvLeft));
+                    let mod_ops = bf[pos..].iter().take_while(|op| match op.op {
+                        BfOp::MvRight | BfOp::MvLeft => {
+                            config.tape == TapeMode::Infinite
+                                || config.pointer_overflow == Overflow::Wrap
+                                || op.op == bf_op.op
+                        }
+                        _ => false,
+                    });
 
                     let delta = mod_ops
                         .map(|op| {
                             pos += 1;
-                            if *op == BfOp::MvRight {
+                            if op.op == BfOp::MvRight {
                                 1
                             } else {
                                 -1
@@ -131,49 +155,86 @@ impl HirGen {
                 }
             };
 
-            result.push(hir_op);
+            // Collapsed ops cover every command that went into them
+            let span = bf_op.span.to(bf[pos - 1].span);
+
+            result.push(Spanned::new(hir_op, span));
         }
 
         result
     }
 }
 
-pub struct HirInterpreter;
+pub struct HirInterpreter {
+    program: Vec<Spanned<HirOp>>,
+    config: Config,
+}
+
+impl Backend<'_> for HirInterpreter {
+    fn compile(program: &[Spanned<BfOp>], config: &Config, _arena: &Bump) -> Result<Self> {
+        Ok(Self {
+            program: HirGen::gen(program, config),
+            config: *config,
+        })
+    }
+
+    fn run(
+        &self,
+        state: &mut BrainfuckState,
+        input: &mut dyn Read,
+        output: &mut dyn Write,
+    ) -> Result<()> {
+        Ok(Self::execute(
+            &self.program,
+            state,
+            input,
+            output,
+            &self.config,
+        )?)
+    }
+}
 
 impl HirInterpreter {
-    pub fn execute(program: &[HirOp]) -> Result<()> {
-        if cfg!(trace) {
+    pub fn execute(
+        program: &[Spanned<HirOp>],
+        state: &mut BrainfuckState,
+        input: &mut dyn Read,
+        output: &mut dyn Write,
+        config: &Config,
+    ) -> Result<(), RuntimeError> {
+        config.check_state(state)?;
+
+        if cfg!(feature = "trace") {
             eprintln!("[Tracing enabled]");
         }
 
-        let branch_table = Self::gen_branch_table(program)?;
+        let branch_table = gen_branch_table(program)?;
 
         let mut instr_pointer = 0;
-        let mut state = BrainfuckState::new();
-
-        let mut stdin = io::stdin().lock();
-        let mut stdout = io::stdout().lock();
+        let mut budget = Budget::new(config);
 
         // Tracing is very simple, only handles non-nested loops
         #[derive(Debug)]
         struct Trace {
-            loc: (/* start */ usize, /* end */ usize),
+            loc: Span,
             hit_count: usize,
             ops: Vec<HirOp>,
         }
         let mut traces = HashMap::new();
         let mut last_trace = Vec::new();
-        let mut last_trace_start = usize::MAX;
+        let mut last_trace_start = Span::default();
+
+        while let Some(Spanned { op: command, span }) = program.get(instr_pointer) {
+            budget.step(*span, state)?;
 
-        while let Some(ref command) = program.get(instr_pointer) {
-            if cfg!(trace) {
+            if cfg!(feature = "trace") {
                 match command {
                     HirOp::BrFor => {
                         last_trace = Vec::new();
-                        last_trace_start = instr_pointer;
+                        last_trace_start = *span;
                     }
                     HirOp::BrBack if !last_trace.is_empty() => {
-                        let loc = (last_trace_start, instr_pointer);
+                        let loc = last_trace_start.to(*span);
                         traces
                             .entry(loc)
                             .and_modify(|t: &mut Trace| t.hit_count += 1)
@@ -185,30 +246,25 @@ impl HirInterpreter {
 
                         last_trace = Vec::new();
                     }
-                    &&command => last_trace.push(command),
+                    &command => last_trace.push(command),
                 }
             }
 
             match command {
                 HirOp::Modify(delta) => {
-                    state.modify_cur_cell_with(|c| {
-                        *c = c.wrapping_add_signed(*delta as i8);
-                    });
+                    state
+                        .add_to_cur_cell(*delta as i128, config)
+                        .ok_or(RuntimeError::CellOverflow { span: *span })?;
                 }
-                HirOp::Move(delta) => state.pos = state.pos.wrapping_add_signed(*delta),
-                HirOp::Out => {
-                    stdout
-                        .write_all(&[state.read_cur_cell()])
-                        .expect("writing to `stdout` failed");
+                HirOp::Move(delta) => {
+                    state.pos = state.cell_at(*delta, config, *span)?;
                 }
-                HirOp::In => {
-                    let mut buff = [0; 1];
-                    stdin
-                        .read_exact(&mut buff)
-                        .expect("reading from `stdin` failed");
-
-                    state.set_cur_cell(buff[0]);
+                HirOp::Out => {
+                    output
+                        .write_all(&[state.read_cur_cell() as u8])
+                        .map_err(|err| RuntimeError::io(err, *span))?;
                 }
+                HirOp::In => state.read_input(input, output, config, *span)?,
                 HirOp::BrFor => {
                     if state.read_cur_cell() == 0 {
                         instr_pointer = branch_table[instr_pointer];
@@ -224,7 +280,7 @@ impl HirInterpreter {
             instr_pointer += 1;
         }
 
-        if cfg!(trace) {
+        if cfg!(feature = "trace") {
             for (_, trace) in traces
                 .iter()
                 .collect::<Vec<_>>()
@@ -259,46 +315,16 @@ impl HirInterpreter {
                 }
 
                 eprintln!(
-                    "Trace: hit_count={}, loc=({},{}), ops={}",
-                    trace.hit_count, trace.loc.0, trace.loc.1, op_str
+                    "Trace: hit_count={}, loc={}..{} ({}), ops={}",
+                    trace.hit_count, trace.loc.start, trace.loc.end, trace.loc, op_str
                 );
             }
         }
 
-        Ok(())
-    }
-
-    fn gen_branch_table(program: &[HirOp]) -> Result<Vec<usize>> {
-        let mut table = vec![0; program.len()];
-
-        let mut instr_pointer = 0;
-
-        while let Some(ref command) = program.get(instr_pointer) {
-            if let HirOp::BrFor = command {
-                let mut depth = 0;
-                let mut pos = instr_pointer;
-
-                loop {
-                    pos += 1;
-
-                    match program.get(pos) {
-                        Some(HirOp::BrFor) => depth += 1,
-                        Some(HirOp::BrBack) if depth > 0 => depth -= 1,
-                        Some(HirOp::BrBack) => {
-                            table[instr_pointer] = pos;
-                            table[pos] = instr_pointer;
+        output
+            .flush()
+            .map_err(|source| RuntimeError::Io { span: None, source })?;
 
-                            break;
-                        }
-                        None => unreachable!("Unterminated bracket, should've been caught earlier"),
-                        _ => {}
-                    }
-                }
-            }
-
-            instr_pointer += 1;
-        }
-
-        Ok(table)
+        Ok(())
     }
 }
@@ -1,3 +1,7 @@
+use std::fmt::{self, Display};
+
+use crate::error::RuntimeError;
+
 pub trait IrLike {
     fn to_compact(&self) -> String;
 }
@@ -14,3 +18,93 @@ impl<T: IrLike> IrLike for [T] {
         r
     }
 }
+
+/// Where in the source an op came from
+#[derive(Debug, Default, PartialEq, Eq, Hash, Copy, Clone)]
+pub struct Span {
+    /// Byte range of the commands making up the op
+    pub start: usize,
+    pub end: usize,
+    /// 1-based line and column (in characters) of `start`
+    pub line: usize,
+    pub column: usize,
+}
+
+impl Span {
+    /// The smallest span covering both `self` and `other`, for ops fused from several others
+    pub fn to(self, other: Span) -> Span {
+        let first = if other.start < self.start {
+            other
+        } else {
+            self
+        };
+
+        Span {
+            start: first.start,
+            end: self.end.max(other.end),
+            line: first.line,
+            column: first.column,
+        }
+    }
+}
+
+impl Display for Span {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.line, self.column)
+    }
+}
+
+/// An op along with where it came from
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub struct Spanned<T> {
+    pub op: T,
+    pub span: Span,
+}
+
+impl<T> Spanned<T> {
+    pub fn new(op: T, span: Span) -> Self {
+        Self { op, span }
+    }
+}
+
+impl<T: IrLike> IrLike for Spanned<T> {
+    fn to_compact(&self) -> String {
+        self.op.to_compact()
+    }
+}
+
+/// Flat IRs mark the start and end of each loop with a pair of ops
+pub trait LoopMarkers: Copy + PartialEq {
+    const BR_FOR: Self;
+    const BR_BACK: Self;
+}
+
+/// Maps each `BrFor` in `program` to its matching `BrBack` and vice versa, other ops map to 0
+///
+/// Programs from the parser always have matching brackets, but hand-built ones might not
+pub fn gen_branch_table<T: LoopMarkers>(
+    program: &[Spanned<T>],
+) -> Result<Vec<usize>, RuntimeError> {
+    let mut table = vec![0; program.len()];
+    let mut open_loops = Vec::new();
+
+    for (pos, command) in program.iter().enumerate() {
+        if command.op == T::BR_FOR {
+            open_loops.push(pos);
+        } else if command.op == T::BR_BACK {
+            let start = open_loops
+                .pop()
+                .ok_or(RuntimeError::UnmatchedBracket { span: command.span })?;
+
+            table[start] = pos;
+            table[pos] = start;
+        }
+    }
+
+    match open_loops.pop() {
+        Some(start) => Err(RuntimeError::UnmatchedBracket {
+            span: program[start].span,
+        }),
+        None => Ok(table),
+    }
+}
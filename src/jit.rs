@@ -1,196 +1,504 @@
-use std::collections::VecDeque;
-use std::{mem, u8};
+use std::{
+    io::{self, Read, Write},
+    mem, ptr,
+};
 
-use anyhow::Result;
-use dynasmrt::{dynasm, AssemblyOffset, DynasmApi, DynasmLabelApi, ExecutableBuffer};
+use anyhow::{bail, Result};
+use bumpalo::Bump;
+use dynasmrt::{AssemblyOffset, ExecutableBuffer};
 use log::trace;
 
-use crate::ir::IrLike;
-use crate::lir::LirOp;
+use crate::backend::Backend;
+use crate::config::{Config, TapeMode};
+use crate::error::RuntimeError;
+use crate::hir::{BfOp, HirGen};
+use crate::ir::{IrLike, Span, Spanned};
+use crate::limit::Budget;
+use crate::lir::{LirGen, LirOp};
+use crate::state::BrainfuckState;
 
-pub struct Jit;
+mod aarch64;
+pub(crate) mod x86_64;
 
-impl Jit {
-    pub fn jit(program: &[LirOp]) -> Result<(ExecutableBuffer, extern "C" fn(*mut u8, *mut u8))> {
-        trace!("Jitting Lir: {}", program.to_compact());
+/// How far apart cells are on the tape, cells are stored as `u32`s whatever their width
+pub(crate) const CELL_BYTES: isize = mem::size_of::<u32>() as isize;
 
-        let mut branch_table = VecDeque::new();
-
-        let mut asm = dynasmrt::aarch64::Assembler::new().unwrap();
-
-        for op in program {
-            match op {
-                LirOp::OffsetModify(delta, offset) => {
-                    let offset = *offset as i64;
-                    let abs_offset = offset.unsigned_abs();
-
-                    match offset {
-                        1.. => dynasm!(asm
-                            ; .arch aarch64
-                            ; mov x4, abs_offset
-                            ; add x5, x0, x4
-                        ),
-                        ..=-1 => dynasm!(asm
-                            ; .arch aarch64
-                            ; mov x4, abs_offset
-                            ; sub x5, x0, x4
-                        ),
-                        _ => dynasm!(asm
-                            ; .arch aarch64
-                            ; mov x5, x0
-                        ),
-                    }
-
-                    let abs_delta = (*delta as i64).unsigned_abs();
-                    if *delta > 0 {
-                        dynasm!(asm
-                            ; .arch aarch64
-                            ; ldrb w2, [x5]
-                            ; mov x3, abs_delta
-                            ; add x2, x2, x3
-                            ; strb w2, [x5]
-                        )
-                    } else {
-                        dynasm!(asm
-                            ; .arch aarch64
-                            ; ldrb w2, [x5]
-                            ; mov x3, abs_delta
-                            ; sub x2, x2, x3
-                            ; strb w2, [x5]
-                        )
-                    }
-                }
-                LirOp::Move(delta) => {
-                    let abs_delta = (*delta as i64).unsigned_abs();
-
-                    if *delta > 0 {
-                        dynasm!(asm
-                            ; .arch aarch64
-                            ; mov x3, abs_delta
-                            ; add x0, x0, x3
-                        )
-                    } else {
-                        dynasm!(asm
-                            ; .arch aarch64
-                            ; mov x3, abs_delta
-                            ; sub x0, x0, x3
-                        )
-                    }
-                }
-                LirOp::WriteZero => {
-                    dynasm!(asm
-                        ; .arch aarch64
-                        ; strb wzr, [x0]
-                    )
-                }
-                LirOp::Hop(delta) => {
-                    let abs_delta = (*delta as i64).unsigned_abs();
-
-                    if *delta > 0 {
-                        dynasm!(asm
-                            ; .arch aarch64
-                            ; mov w3, abs_delta
-                            ; start:
-                            ; ldrb w2, [x0]
-                            ; cbz w2, >end
-                            ; add x0, x0, x3
-                            ; b <start
-                            ; end:
-                        )
-                    } else {
-                        dynasm!(asm
-                            ; .arch aarch64
-                            ; mov w3, abs_delta
-                            ; start:
-                            ; ldrb w2, [x0]
-                            ; cbz w2, >end
-                            ; sub x0, x0, x3
-                            ; b <start
-                            ; end:
-                        )
-                    }
-                }
-                LirOp::MoveCell(delta) => {
-                    let abs_delta = (*delta as i64).unsigned_abs();
-
-                    dynasm!(asm
-                        ; .arch aarch64
-                        ; mov w4, abs_delta
-                    );
-
-                    if *delta > 0 {
-                        dynasm!(asm
-                            ; .arch aarch64
-                            ; add x5, x0, x4
-                        )
-                    } else {
-                        dynasm!(asm
-                            ; .arch aarch64
-                            ; sub x5, x0, x4
-                        )
-                    }
-
-                    dynasm!(asm
-                        ; .arch aarch64
-                        ; ldrb w2, [x0]
-                        ; cbz w2, >skip
-                        ; strb wzr, [x0]
-                        ; ldrb w3, [x5]
-                        ; add w2, w2, w3
-                        ; and w2, w2, #0xFF
-                        ; strb w2, [x5]
-                        ; skip:
-                    )
-                }
-                LirOp::In => {} //todo!("this is gonna be a pain"),
-                LirOp::Out => dynasm!(asm
-                    ; .arch aarch64
-                    ; ldrb w2, [x0]
-                    ; strb w2, [x1]
-                    ; add x1, x1, #1
-                ),
-                LirOp::BrFor => {
-                    let back_branch = asm.new_dynamic_label();
-                    let for_branch = asm.new_dynamic_label();
-
-                    branch_table.push_back((for_branch, back_branch));
-
-                    dynasm!(asm
-                        ; .arch aarch64
-                        ; ldrb w2, [x0]
-                        ; cbz w2, =>for_branch
-                        ; .align 4
-                        ; =>back_branch
-                    )
-                }
-                LirOp::BrBack => {
-                    let (for_branch, back_branch) =
-                        branch_table.pop_back().expect("unmatched loop");
-
-                    dynasm!(asm
-                        ; .arch aarch64
-                        ; ldrb w2, [x0]
-                        ; cbnz w2, =>back_branch
-                        ; .align 4
-                        ; =>for_branch
-                    )
+/// How many cells an infinite tape has either side of the pointer when jitted code starts, so it
+/// only has to grow for programs which wander a long way
+const INFINITE_HEADROOM: usize = 1 << 16;
+
+/// How many bytes of output jitted code buffers before calling back into the runtime to flush them
+const OUT_CAPACITY: usize = 4096;
+
+/// Returned by the read callback to leave the current cell untouched
+pub(crate) const READ_UNCHANGED: i64 = -1;
+/// Returned by the read callback when the program must abort, the error is stored in the context
+pub(crate) const READ_ERROR: i64 = -2;
+
+/// State shared between jitted code and the Rust runtime
+///
+/// Jitted code only ever touches the function pointers, the tape bounds, the output buffer and the
+/// loop counter, which it finds through `mem::offset_of!`
+///
+/// Executables built by [`crate::aot`] set up the same layout in memory of their own, with a
+/// runtime written in assembly behind the function pointers
+#[repr(C)]
+pub struct JitContext<'a> {
+    read: extern "C" fn(ctx: *mut JitContext, op: u64) -> i64,
+    flush: extern "C" fn(ctx: *mut JitContext, op: u64) -> u64,
+    fault:
+        extern "C" fn(ctx: *mut JitContext, cur: *mut u32, target: *mut u32, op: u64) -> *mut u32,
+    overflow: extern "C" fn(ctx: *mut JitContext, op: u64),
+    tick: extern "C" fn(ctx: *mut JitContext, cur: *mut u32, op: u64) -> u64,
+
+    // Loop iterations left before jitted code calls `tick`, only counted if the program has limits
+    ticks: u64,
+
+    // Every cell jitted code touches is in `tape_lo..tape_hi`
+    tape_lo: *mut u32,
+    tape_hi: *mut u32,
+
+    // Where the pointer was when jitted code returned
+    cell: *mut u32,
+
+    out_len: usize,
+    out_buf: [u8; OUT_CAPACITY],
+
+    // Where each op came from, for reporting faults
+    spans: &'a [Span],
+    input: &'a mut dyn Read,
+    output: &'a mut dyn Write,
+    // Owns the tape, so an infinite one can be grown
+    state: &'a mut BrainfuckState,
+    config: Config,
+    budget: Budget,
+    error: Option<RuntimeError>,
+}
+
+impl JitContext<'_> {
+    pub(crate) const READ: i32 = mem::offset_of!(JitContext, read) as i32;
+    pub(crate) const FLUSH: i32 = mem::offset_of!(JitContext, flush) as i32;
+    pub(crate) const FAULT: i32 = mem::offset_of!(JitContext, fault) as i32;
+    pub(crate) const OVERFLOW: i32 = mem::offset_of!(JitContext, overflow) as i32;
+    const TICK: i32 = mem::offset_of!(JitContext, tick) as i32;
+    const TICKS: i32 = mem::offset_of!(JitContext, ticks) as i32;
+    pub(crate) const TAPE_LO: i32 = mem::offset_of!(JitContext, tape_lo) as i32;
+    pub(crate) const TAPE_HI: i32 = mem::offset_of!(JitContext, tape_hi) as i32;
+    const CELL: i32 = mem::offset_of!(JitContext, cell) as i32;
+    pub(crate) const OUT_LEN: i32 = mem::offset_of!(JitContext, out_len) as i32;
+    pub(crate) const OUT_BUF: i32 = mem::offset_of!(JitContext, out_buf) as i32;
+
+    fn flush_output(&mut self) -> io::Result<()> {
+        let len = mem::take(&mut self.out_len);
+
+        self.output.write_all(&self.out_buf[..len])?;
+        self.output.flush()
+    }
+}
+
+/// Reads a single byte for the `,` at the `op`th instruction
+///
+/// Returns the new value of the cell, or one of [`READ_UNCHANGED`] or [`READ_ERROR`]
+extern "C" fn jit_read(ctx: *mut JitContext, op: u64) -> i64 {
+    // SAFETY: jitted code passes back the pointer we gave it, which outlives the call
+    let ctx = unsafe { &mut *ctx };
+    let span = ctx.spans[op as usize];
+
+    // Flush first, as the interpreters do
+    let value = ctx
+        .flush_output()
+        .and_then(|()| ctx.config.eof.read(ctx.input));
+
+    match value {
+        Ok(Some(value)) => value as i64,
+        Ok(None) => READ_UNCHANGED,
+        Err(err) => {
+            ctx.error = Some(RuntimeError::io(err, span));
+            READ_ERROR
+        }
+    }
+}
+
+/// Writes out the output buffer once the `.` at the `op`th instruction has filled it
+///
+/// Returns non-zero if writing failed, the error is stored in the context
+extern "C" fn jit_flush(ctx: *mut JitContext, op: u64) -> u64 {
+    // SAFETY: jitted code passes back the pointer we gave it, which outlives the call
+    let ctx = unsafe { &mut *ctx };
+
+    match ctx.flush_output() {
+        Ok(()) => 0,
+        Err(err) => {
+            ctx.error = Some(RuntimeError::io(err, ctx.spans[op as usize]));
+            1
+        }
+    }
+}
+
+/// Called when the `op`th instruction is about to touch a cell outside of the tape
+///
+/// Returns the (possibly relocated) current cell pointer to carry on with, or null to abort. An
+/// infinite tape is grown to take in the cell. Otherwise, if the pointer itself has moved off the
+/// tape, it is wrapped or clamped back on to it unless overflow traps
+extern "C" fn jit_fault(
+    ctx: *mut JitContext,
+    cur: *mut u32,
+    target: *mut u32,
+    op: u64,
+) -> *mut u32 {
+    // SAFETY: jitted code passes back the pointer we gave it, which outlives the call
+    let ctx = unsafe { &mut *ctx };
+
+    // The target can be anywhere, so work with plain addresses rather than `offset_from`
+    let pos = (target as isize).wrapping_sub(ctx.tape_lo as isize) / CELL_BYTES;
+    let len = (ctx.tape_hi as usize - ctx.tape_lo as usize) / CELL_BYTES as usize;
+
+    if ctx.config.tape == TapeMode::Infinite {
+        let max = ctx.config.max_tape_len;
+        let needed = if pos < 0 {
+            len.saturating_add(pos.unsigned_abs())
+        } else {
+            pos as usize + 1
+        };
+
+        if needed > max {
+            ctx.error = Some(RuntimeError::TapeTooLarge {
+                span: Some(ctx.spans[op as usize]),
+                len: needed,
+                max,
+            });
+
+            return ptr::null_mut();
+        }
+
+        let cur = (cur as isize - ctx.tape_lo as isize) / CELL_BYTES;
+        let state = &mut *ctx.state;
+
+        // Grow by up to the length of the tape, so jitted code rarely ends up back here
+        let added = if pos < 0 {
+            state.grow_left(pos.unsigned_abs(), max)
+        } else {
+            state.cells.resize(needed.max(2 * len).min(max), 0);
+            0
+        };
+
+        let tape = state.cells.as_mut_ptr_range();
+        ctx.tape_lo = tape.start;
+        ctx.tape_hi = tape.end;
+
+        // SAFETY: the current cell was on the tape, and is still there `added` cells further along
+        return unsafe { tape.start.offset(cur + added as isize) };
+    }
+
+    if target == cur {
+        if let Some(pos) = ctx.config.pointer_at(pos) {
+            // SAFETY: the position is on the tape, which is `len` cells long
+            return unsafe { ctx.tape_lo.add(pos) };
+        }
+    }
+
+    ctx.error = Some(RuntimeError::PointerOutOfRange {
+        span: ctx.spans[op as usize],
+        cell: pos,
+        len,
+    });
+
+    ptr::null_mut()
+}
+
+/// Called when the `op`th instruction overflows a cell and overflow traps, jitted code aborts
+/// straight after
+extern "C" fn jit_overflow(ctx: *mut JitContext, op: u64) {
+    // SAFETY: jitted code passes back the pointer we gave it, which outlives the call
+    let ctx = unsafe { &mut *ctx };
+
+    ctx.error = Some(RuntimeError::CellOverflow {
+        span: ctx.spans[op as usize],
+    });
+}
+
+/// Called when the loop ending at the `op`th instruction has used up the iterations it was given
+///
+/// Returns non-zero if the program has run into one of its limits, the error is stored in the
+/// context
+extern "C" fn jit_tick(ctx: *mut JitContext, cur: *mut u32, op: u64) -> u64 {
+    // SAFETY: jitted code passes back the pointer we gave it, which outlives the call
+    let ctx = unsafe { &mut *ctx };
+
+    // The state's pointer is only brought up to date when jitted code returns, but the error needs
+    // it now
+    // SAFETY: jitted code only ever leaves the pointer on the tape
+    ctx.state.pos = unsafe { cur.offset_from(ctx.tape_lo) } as usize;
+
+    match ctx.budget.step(ctx.spans[op as usize], ctx.state) {
+        Ok(()) => {
+            // The iteration which got us here was the step just taken
+            ctx.ticks = ctx.budget.take_remaining() + 1;
+            0
+        }
+        Err(err) => {
+            ctx.error = Some(err.into());
+            1
+        }
+    }
+}
+
+/// Which end of the tape a bounds check guards against
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Bound {
+    Lower,
+    Upper,
+}
+
+/// Tracks which offsets from the current cell are known to be on the tape, so the backends only
+/// emit a bounds check the first time a straight-line run of code reaches further out, and only
+/// against the end of the tape it is heading towards
+///
+/// Always covers at least one cell, and as the tape is contiguous, every cell in between two known
+/// cells is known too
+#[derive(Debug, Clone, Copy, Default)]
+struct CheckedRange {
+    min: isize,
+    max: isize,
+}
+
+impl CheckedRange {
+    /// Forget everything except the current cell, e.g. at a branch target
+    fn reset(&mut self) {
+        *self = Self::default();
+    }
+
+    /// The pointer moved by `delta`, so what we know is now that much further away
+    fn shift(&mut self, delta: isize) {
+        self.min -= delta;
+        self.max -= delta;
+    }
+
+    /// Returns which bound `offset` needs checking against, if any
+    fn bound_for(&self, offset: isize) -> Option<Bound> {
+        if offset < self.min {
+            Some(Bound::Lower)
+        } else if offset > self.max {
+            Some(Bound::Upper)
+        } else {
+            None
+        }
+    }
+
+    /// Like [`CheckedRange::bound_for`], but assumes the check will be emitted if one is needed
+    ///
+    /// Only valid for checks which run unconditionally
+    fn needs_check(&mut self, offset: isize) -> Option<Bound> {
+        let bound = self.bound_for(offset);
+
+        self.min = self.min.min(offset);
+        self.max = self.max.max(offset);
+
+        bound
+    }
+}
+
+/// The lowest and highest offsets a `MulAdd` adds to
+fn mul_add_extent(targets: &[(isize, isize)]) -> (isize, isize) {
+    let offsets = targets.iter().map(|&(offset, _)| offset);
+
+    (
+        offsets.clone().min().unwrap_or(0),
+        offsets.max().unwrap_or(0),
+    )
+}
+
+/// Collects the cells the straight-line run of code starting at `program[start]` is certain to
+/// touch, as `(op, offset)` pairs
+///
+/// The run stops before anything which moves the pointer, branches or has side effects beyond the
+/// tape, and the index of that op is returned alongside
+fn straight_line_accesses(
+    program: &[Spanned<LirOp>],
+    start: usize,
+) -> (Vec<(usize, isize)>, usize) {
+    let mut accesses = Vec::new();
+
+    for (i, op) in program.iter().enumerate().skip(start) {
+        match op.op {
+            LirOp::OffsetModify(_, offset) => accesses.push((i, offset)),
+            // The targets of a `MoveCell` or `MulAdd` are only touched conditionally, so are checked
+            // in place
+            LirOp::WriteZero | LirOp::MoveCell(_) | LirOp::MulAdd(_) => accesses.push((i, 0)),
+            LirOp::Meta(_) => {}
+            LirOp::Move(_)
+            | LirOp::Hop(_)
+            | LirOp::In
+            | LirOp::Out
+            | LirOp::BrFor
+            | LirOp::BrBack => return (accesses, i),
+        }
+    }
+
+    (accesses, program.len())
+}
+
+/// The lowest and highest offsets touched after the `Move` at `program[at]`, up to the end of the
+/// straight-line run following it, along with the ops touching them
+///
+/// Checking just these two after moving covers everything in between
+fn move_extent(program: &[Spanned<LirOp>], at: usize) -> ((usize, isize), (usize, isize)) {
+    let (accesses, _) = straight_line_accesses(program, at + 1);
+
+    let lowest = accesses.iter().copied().min_by_key(|&(_, offset)| offset);
+    let highest = accesses.iter().copied().max_by_key(|&(_, offset)| offset);
+
+    (
+        lowest.filter(|&(_, offset)| offset < 0).unwrap_or((at, 0)),
+        highest.filter(|&(_, offset)| offset > 0).unwrap_or((at, 0)),
+    )
+}
+
+/// If the loop starting at `program[start]` never moves the pointer and has no side effects other
+/// than on the tape, returns the cells each iteration is certain to touch as `(op, offset)` pairs
+///
+/// Checking these once before entering the loop lets the body run without any bounds checks
+fn stationary_loop_accesses(
+    program: &[Spanned<LirOp>],
+    start: usize,
+) -> Option<Vec<(usize, isize)>> {
+    match straight_line_accesses(program, start + 1) {
+        (accesses, end) if program.get(end).map(|op| op.op) == Some(LirOp::BrBack) => {
+            Some(accesses)
+        }
+        _ => None,
+    }
+}
+
+/// The entry point of jitted code, returns non-zero if execution was aborted
+type JitFn = extern "C" fn(cells: *mut u32, ctx: *mut JitContext) -> u64;
+
+pub struct JitProgram {
+    // Backing memory for `func`, must outlive it
+    _buffer: ExecutableBuffer,
+    func: JitFn,
+    spans: Vec<Span>,
+    config: Config,
+}
+
+impl<'a> Backend<'a> for JitProgram {
+    fn compile(program: &[Spanned<BfOp>], config: &Config, arena: &'a Bump) -> Result<Self> {
+        if !Jit::is_supported() {
+            bail!("The JIT is currently only supported on ARM64 and x86-64");
+        }
+
+        let hir = HirGen::gen(program, config);
+        let lir = LirGen::gen_ir(&hir, config, arena)?;
+
+        Jit::jit(&lir, config)
+    }
+
+    fn run(
+        &self,
+        state: &mut BrainfuckState,
+        input: &mut dyn Read,
+        output: &mut dyn Write,
+    ) -> Result<()> {
+        self.config.check_state(state)?;
+
+        let len = match self.config.tape {
+            TapeMode::Fixed => self.config.tape_len,
+            TapeMode::Infinite => {
+                // Only take as much headroom as the tape is allowed
+                let max = self.config.max_tape_len;
+                let headroom = INFINITE_HEADROOM.min((max - state.cells.len()) / 2);
+
+                if state.pos < headroom {
+                    state.grow_left(headroom - state.pos, max);
                 }
-                LirOp::Meta(_) => { /* meta nodes ignored */ }
+
+                // Jitted code assumes the current cell is on the tape, which `check_state` has
+                // made sure fits
+                (state.pos + headroom.max(1)).min(max)
             }
+        };
+
+        if state.cells.len() < len {
+            state.cells.resize(len, 0);
+        }
+
+        let tape = match self.config.tape {
+            TapeMode::Fixed => state.cells[..len].as_mut_ptr_range(),
+            TapeMode::Infinite => state.cells.as_mut_ptr_range(),
+        };
+        // SAFETY: we just made sure `pos` is on the tape
+        let start = unsafe { tape.start.add(state.pos) };
+
+        let mut ctx = JitContext {
+            read: jit_read,
+            flush: jit_flush,
+            fault: jit_fault,
+            overflow: jit_overflow,
+            tick: jit_tick,
+            // The first iteration calls `tick`, which hands out the first steps
+            ticks: 1,
+            tape_lo: tape.start,
+            tape_hi: tape.end,
+            cell: start,
+            out_len: 0,
+            out_buf: [0; OUT_CAPACITY],
+            input,
+            output,
+            spans: &self.spans,
+            state,
+            config: self.config,
+            budget: Budget::new(&self.config),
+            error: None,
+        };
+
+        let status = (self.func)(start, &mut ctx);
+
+        // Moves are only checked when the cells they reach are used, so a program which traps can
+        // leave the pointer anywhere. Keep it on the tape, so the state can still be run from
+        let pos = (ctx.cell as isize).wrapping_sub(ctx.tape_lo as isize) / CELL_BYTES;
+        let len = (ctx.tape_hi as usize - ctx.tape_lo as usize) / CELL_BYTES as usize;
+        ctx.state.pos = pos.clamp(0, len as isize - 1) as usize;
+
+        // Whatever was output before an abort is still worth showing
+        if let Err(source) = ctx.flush_output() {
+            ctx.error
+                .get_or_insert(RuntimeError::Io { span: None, source });
         }
 
-        assert!(branch_table.is_empty());
+        match ctx.error {
+            Some(err) => Err(err.into()),
+            None if status != 0 => bail!("Jitted code aborted with status {status}"),
+            None => Ok(()),
+        }
+    }
+}
+
+pub struct Jit;
+
+impl Jit {
+    /// Whether the JIT can emit code for the architecture we are running on
+    pub const fn is_supported() -> bool {
+        cfg!(any(target_arch = "aarch64", target_arch = "x86_64"))
+    }
+
+    pub fn jit(program: &[Spanned<LirOp>], config: &Config) -> Result<JitProgram> {
+        trace!("Jitting Lir: {}", program.to_compact());
 
-        dynasm!(asm
-            ; .arch aarch64
-            ; ret
-        );
+        let buffer = if cfg!(target_arch = "aarch64") {
+            aarch64::gen(program, config)?
+        } else if cfg!(target_arch = "x86_64") {
+            x86_64::gen(program, config)?
+        } else {
+            bail!("The JIT is not supported on this architecture");
+        };
 
-        let func = asm.finalize().expect("asm gen failed");
-        // Buffer `func` stays around forever, no need to return it
-        let func_ptr: extern "C" fn(cells: *mut u8, buff: *mut u8) -> () =
-            unsafe { mem::transmute(func.ptr(AssemblyOffset(0))) };
+        let func: JitFn = unsafe { mem::transmute(buffer.ptr(AssemblyOffset(0))) };
 
-        Ok((func, func_ptr))
+        Ok(JitProgram {
+            _buffer: buffer,
+            func,
+            spans: program.iter().map(|op| op.span).collect(),
+            config: *config,
+        })
     }
 }
@@ -0,0 +1,690 @@
+use std::collections::VecDeque;
+
+use anyhow::Result;
+use dynasmrt::{
+    aarch64::Assembler, dynasm, DynamicLabel, DynasmApi, DynasmLabelApi, ExecutableBuffer,
+};
+
+use super::{
+    move_extent, mul_add_extent, stationary_loop_accesses, Bound, CheckedRange, JitContext,
+    CELL_BYTES,
+};
+use crate::{
+    config::{CellWidth, Config, Overflow},
+    error::RuntimeError,
+    ir::Spanned,
+    lir::LirOp,
+};
+
+/// Emits AArch64 code for `program`
+///
+/// On entry, `x0` points to the first cell and `x1` to the [`JitContext`]. These live in the
+/// callee-saved `x19` and `x20` for the rest of the function so they survive calls into the
+/// runtime. The start and end of the tape are cached in `x21` and `x22` for bounds checks
+///
+/// Cells take up 4 bytes each, but only the low `width` bits are ever read or written
+pub fn gen(program: &[Spanned<LirOp>], config: &Config) -> Result<ExecutableBuffer> {
+    let mut branch_table = VecDeque::new();
+
+    let mut asm = Assembler::new().unwrap();
+
+    let width = config.cell_width;
+    let abort = asm.new_dynamic_label();
+    let mut checked = CheckedRange::default();
+    let mut faults = Vec::new();
+    let mut overflows = Vec::new();
+    let mut ticks = Vec::new();
+    let limited = config.is_limited();
+
+    dynasm!(asm
+        ; .arch aarch64
+        ; stp x29, x30, [sp, #-48]!
+        ; mov x29, sp
+        ; stp x19, x20, [sp, #16]
+        ; stp x21, x22, [sp, #32]
+        ; mov x19, x0
+        ; mov x20, x1
+    );
+
+    load_tape_bounds(&mut asm);
+
+    for (i, op) in program.iter().enumerate() {
+        match &op.op {
+            LirOp::OffsetModify(delta, offset) => {
+                if let Some(bound) = checked.needs_check(*offset) {
+                    check_offset(&mut asm, &mut faults, i, *offset, bound);
+                }
+
+                cell_address(&mut asm, 5, *offset);
+
+                let abs_delta = (*delta as i64).unsigned_abs();
+                load_imm(&mut asm, 3, abs_delta);
+                load(&mut asm, width, 2, 5);
+
+                if *delta > 0 {
+                    dynasm!(asm
+                        ; .arch aarch64
+                        ; add x2, x2, x3
+                    )
+                } else {
+                    dynasm!(asm
+                        ; .arch aarch64
+                        ; sub x2, x2, x3
+                    )
+                }
+
+                // The cell was zero extended, so this works out the true result in 64 bits
+                if config.cell_overflow != Overflow::Wrap {
+                    let label = asm.new_dynamic_label();
+                    overflows.push((label, i));
+
+                    check_range(&mut asm, width, 2, config.cell_overflow, label);
+                }
+
+                store(&mut asm, width, 2, 5);
+            }
+            LirOp::Move(delta) => {
+                let abs_delta = (*delta as i64 * CELL_BYTES as i64).unsigned_abs();
+                load_imm(&mut asm, 3, abs_delta);
+
+                if *delta > 0 {
+                    dynasm!(asm
+                        ; .arch aarch64
+                        ; add x19, x19, x3
+                    )
+                } else {
+                    dynasm!(asm
+                        ; .arch aarch64
+                        ; sub x19, x19, x3
+                    )
+                }
+
+                if config.moves_are_exact() {
+                    // Check the whole stretch of tape we're about to use in one go
+                    checked.shift(*delta);
+
+                    let (lowest, highest) = move_extent(program, i);
+                    for (op, offset) in [lowest, highest] {
+                        if let Some(bound) = checked.needs_check(offset) {
+                            check_offset(&mut asm, &mut faults, op, offset, bound);
+                        }
+                    }
+                } else if *delta != 0 {
+                    // The fault handler wraps or clamps the pointer back on to the tape, after
+                    // which all we know is that the current cell is on it
+                    let bound = if *delta > 0 {
+                        Bound::Upper
+                    } else {
+                        Bound::Lower
+                    };
+
+                    check_offset(&mut asm, &mut faults, i, 0, bound);
+                    checked.reset();
+                }
+            }
+            LirOp::WriteZero => store_zero(&mut asm, width),
+            LirOp::Hop(delta) => {
+                // Each hop starts from a cell on the tape, so can only fall off the end it's heading
+                let bound = if *delta > 0 {
+                    Bound::Upper
+                } else {
+                    Bound::Lower
+                };
+
+                let start = asm.new_dynamic_label();
+
+                load(&mut asm, width, 2, 19);
+                dynasm!(asm
+                    ; .arch aarch64
+                    ; cbz w2, >end
+                    ; =>start
+                );
+
+                // Loaded inside the loop as the fault handler is free to clobber it
+                let abs_delta = (*delta as i64 * CELL_BYTES as i64).unsigned_abs();
+                load_imm(&mut asm, 3, abs_delta);
+
+                if *delta > 0 {
+                    dynasm!(asm
+                        ; .arch aarch64
+                        ; add x19, x19, x3
+                    )
+                } else {
+                    dynasm!(asm
+                        ; .arch aarch64
+                        ; sub x19, x19, x3
+                    )
+                }
+
+                check_offset(&mut asm, &mut faults, i, 0, bound);
+                checked.reset();
+
+                branch_back(&mut asm, width, limited.then_some(&mut ticks), i, start);
+                dynasm!(asm
+                    ; .arch aarch64
+                    ; end:
+                )
+            }
+            LirOp::MoveCell(delta) => {
+                load(&mut asm, width, 2, 19);
+                dynasm!(asm
+                    ; .arch aarch64
+                    ; cbz w2, >skip
+                );
+
+                // The target is only touched if the loop would have run, so any check has to be
+                // skipped along with it
+                if let Some(bound) = checked.bound_for(*delta) {
+                    check_offset(&mut asm, &mut faults, i, *delta, bound);
+
+                    // Calling the fault handler clobbers the cell we loaded
+                    load(&mut asm, width, 2, 19);
+                }
+
+                cell_address(&mut asm, 5, *delta);
+
+                store_zero(&mut asm, width);
+                load(&mut asm, width, 3, 5);
+                dynasm!(asm
+                    ; .arch aarch64
+                    ; add x2, x2, x3
+                );
+
+                if config.cell_overflow != Overflow::Wrap {
+                    let label = asm.new_dynamic_label();
+                    overflows.push((label, i));
+
+                    check_range(&mut asm, width, 2, config.cell_overflow, label);
+                }
+
+                store(&mut asm, width, 2, 5);
+                dynasm!(asm
+                    ; .arch aarch64
+                    ; skip:
+                )
+            }
+            LirOp::MulAdd(targets) => {
+                let (lowest, highest) = mul_add_extent(targets);
+
+                load(&mut asm, width, 2, 19);
+                dynasm!(asm
+                    ; .arch aarch64
+                    ; cbz w2, >skip
+                );
+
+                // As with `MoveCell`, the targets are only checked if they will be touched
+                for offset in [lowest, highest] {
+                    if let Some(bound) = checked.bound_for(offset) {
+                        check_offset(&mut asm, &mut faults, i, offset, bound);
+                    }
+                }
+
+                // Calling the fault handler clobbers the cell we loaded
+                load(&mut asm, width, 2, 19);
+
+                let label = asm.new_dynamic_label();
+                if config.cell_overflow != Overflow::Wrap {
+                    overflows.push((label, i));
+                }
+
+                for &(offset, factor) in targets.iter() {
+                    cell_address(&mut asm, 5, offset);
+                    load(&mut asm, width, 3, 5);
+
+                    if config.cell_overflow == Overflow::Wrap {
+                        // Only the low bits of the product end up in the cell
+                        load_imm(&mut asm, 6, width.wrap(factor as u32).into());
+
+                        dynasm!(asm
+                            ; .arch aarch64
+                            ; madd w3, w2, w6, w3
+                        );
+                    } else {
+                        // No cell and factor can overflow 64 bits, so work out the true result
+                        load_imm(&mut asm, 6, factor as i64 as u64);
+
+                        dynasm!(asm
+                            ; .arch aarch64
+                            ; madd x3, x2, x6, x3
+                        );
+
+                        check_range(&mut asm, width, 3, config.cell_overflow, label);
+                    }
+
+                    store(&mut asm, width, 3, 5);
+                }
+
+                store_zero(&mut asm, width);
+                dynasm!(asm
+                    ; .arch aarch64
+                    ; skip:
+                )
+            }
+            LirOp::In => {
+                load_imm(&mut asm, 1, i as u64);
+
+                dynasm!(asm
+                    ; .arch aarch64
+                    ; mov x0, x20
+                    ; ldr x16, [x20, JitContext::READ as u32]
+                    ; blr x16
+                    ; cmn x0, #1
+                    ; b.eq >done
+                    ; b.lt =>abort
+                );
+
+                store(&mut asm, width, 0, 19);
+                dynasm!(asm
+                    ; .arch aarch64
+                    ; done:
+                )
+            }
+            LirOp::Out => {
+                dynasm!(asm
+                    ; .arch aarch64
+                    ; ldr x2, [x20, JitContext::OUT_LEN as u32]
+                    ; mov x3, super::OUT_CAPACITY as u64
+                    ; cmp x2, x3
+                    ; b.lo >store
+                    ; mov x0, x20
+                );
+
+                load_imm(&mut asm, 1, i as u64);
+
+                dynasm!(asm
+                    ; .arch aarch64
+                    ; ldr x16, [x20, JitContext::FLUSH as u32]
+                    ; blr x16
+                    ; cbnz x0, =>abort
+                    ; mov x2, xzr
+                    ; store:
+                    // Output is the low byte of the cell, whatever its width
+                    ; ldrb w3, [x19]
+                    ; add x4, x20, JitContext::OUT_BUF as u32
+                    ; strb w3, [x4, x2]
+                    ; add x2, x2, #1
+                    ; str x2, [x20, JitContext::OUT_LEN as u32]
+                )
+            }
+            LirOp::BrFor => {
+                let back_branch = asm.new_dynamic_label();
+                let for_branch = asm.new_dynamic_label();
+
+                load(&mut asm, width, 2, 19);
+                dynasm!(asm
+                    ; .arch aarch64
+                    ; cbz w2, =>for_branch
+                );
+
+                // What we know on exit is what we knew on entry, if the pointer can't have moved
+                let on_exit = match stationary_loop_accesses(program, i) {
+                    Some(accesses) => {
+                        let on_entry = checked;
+
+                        for (op, offset) in accesses {
+                            if let Some(bound) = checked.needs_check(offset) {
+                                check_offset(&mut asm, &mut faults, op, offset, bound);
+                            }
+                        }
+
+                        on_entry
+                    }
+                    None => {
+                        checked.reset();
+                        CheckedRange::default()
+                    }
+                };
+
+                branch_table.push_back((op.span, for_branch, back_branch, on_exit));
+
+                dynasm!(asm
+                    ; .arch aarch64
+                    ; .align 4
+                    ; =>back_branch
+                );
+            }
+            LirOp::BrBack => {
+                let (_, for_branch, back_branch, on_exit) = branch_table
+                    .pop_back()
+                    .ok_or(RuntimeError::UnmatchedBracket { span: op.span })?;
+
+                branch_back(
+                    &mut asm,
+                    width,
+                    limited.then_some(&mut ticks),
+                    i,
+                    back_branch,
+                );
+                dynasm!(asm
+                    ; .arch aarch64
+                    ; .align 4
+                    ; =>for_branch
+                );
+
+                checked = on_exit;
+            }
+            LirOp::Meta(_) => { /* meta nodes ignored */ }
+        }
+    }
+
+    // Only the innermost unclosed loop is reported, as `tree::build` does
+    if let Some(&(open, ..)) = branch_table.back() {
+        return Err(RuntimeError::UnmatchedBracket { span: open }.into());
+    }
+
+    dynasm!(asm
+        ; .arch aarch64
+        ; mov x0, xzr
+        ; b >epilogue
+    );
+
+    // Failed bounds checks are rare, so keep them out of the way of the hot code
+    for fault in faults {
+        dynasm!(asm
+            ; .arch aarch64
+            ; =>fault.label
+        );
+
+        cell_address(&mut asm, 2, fault.offset);
+        load_imm(&mut asm, 3, fault.op as u64);
+
+        dynasm!(asm
+            ; .arch aarch64
+            ; mov x0, x20
+            ; mov x1, x19
+            ; ldr x16, [x20, JitContext::FAULT as u32]
+            ; blr x16
+            ; cbz x0, =>abort
+            ; mov x19, x0
+        );
+
+        load_tape_bounds(&mut asm);
+
+        dynasm!(asm
+            ; .arch aarch64
+            ; b =>fault.resume
+        );
+    }
+
+    for (label, op) in overflows {
+        dynasm!(asm
+            ; .arch aarch64
+            ; =>label
+            ; mov x0, x20
+        );
+
+        load_imm(&mut asm, 1, op as u64);
+
+        dynasm!(asm
+            ; .arch aarch64
+            ; ldr x16, [x20, JitContext::OVERFLOW as u32]
+            ; blr x16
+            ; b =>abort
+        );
+    }
+
+    for tick in ticks {
+        dynasm!(asm
+            ; .arch aarch64
+            ; =>tick.label
+            ; mov x0, x20
+            ; mov x1, x19
+        );
+
+        load_imm(&mut asm, 2, tick.op as u64);
+
+        dynasm!(asm
+            ; .arch aarch64
+            ; ldr x16, [x20, JitContext::TICK as u32]
+            ; blr x16
+            ; cbnz x0, =>abort
+            ; b =>tick.resume
+        );
+    }
+
+    dynasm!(asm
+        ; .arch aarch64
+        ; =>abort
+        ; mov x0, #1
+        ; epilogue:
+        ; str x19, [x20, JitContext::CELL as u32]
+        ; ldp x21, x22, [sp, #32]
+        ; ldp x19, x20, [sp, #16]
+        ; ldp x29, x30, [sp], #48
+        ; ret
+    );
+
+    Ok(asm.finalize().expect("asm gen failed"))
+}
+
+/// A loop which has used up the iterations it was given, emitted after the body of the function
+struct Tick {
+    label: DynamicLabel,
+    resume: DynamicLabel,
+    op: usize,
+}
+
+/// Jumps back to the start of a loop at `target` if the current cell is non-zero
+///
+/// If the program has limits, `ticks` is given and each iteration is counted, jumping to a stub
+/// which calls the runtime once the loop has used up the iterations it was given
+fn branch_back(
+    asm: &mut Assembler,
+    width: CellWidth,
+    ticks: Option<&mut Vec<Tick>>,
+    op: usize,
+    target: DynamicLabel,
+) {
+    load(asm, width, 2, 19);
+
+    let Some(ticks) = ticks else {
+        dynasm!(asm
+            ; .arch aarch64
+            ; cbnz w2, =>target
+        );
+
+        return;
+    };
+
+    let tick = Tick {
+        label: asm.new_dynamic_label(),
+        resume: target,
+        op,
+    };
+
+    dynasm!(asm
+        ; .arch aarch64
+        ; cbz w2, >done
+        ; ldr x3, [x20, JitContext::TICKS as u32]
+        ; subs x3, x3, #1
+        ; str x3, [x20, JitContext::TICKS as u32]
+        ; b.ne =>target
+        ; b =>tick.label
+        ; done:
+    );
+
+    ticks.push(tick);
+}
+
+/// A failed bounds check, emitted after the body of the function
+struct Fault {
+    label: DynamicLabel,
+    resume: DynamicLabel,
+    op: usize,
+    offset: isize,
+}
+
+/// Checks the cell at `offset` from the current one is within `bound`, jumping to a stub which
+/// calls the fault handler if it isn't
+fn check_offset(
+    asm: &mut Assembler,
+    faults: &mut Vec<Fault>,
+    op: usize,
+    offset: isize,
+    bound: Bound,
+) {
+    let fault = Fault {
+        label: asm.new_dynamic_label(),
+        resume: asm.new_dynamic_label(),
+        op,
+        offset,
+    };
+
+    cell_address(asm, 5, offset);
+
+    match bound {
+        Bound::Lower => dynasm!(asm
+            ; .arch aarch64
+            ; cmp x5, x21
+            ; b.lo =>fault.label
+        ),
+        Bound::Upper => dynasm!(asm
+            ; .arch aarch64
+            ; cmp x5, x22
+            ; b.hs =>fault.label
+        ),
+    }
+
+    dynasm!(asm
+        ; .arch aarch64
+        ; =>fault.resume
+    );
+
+    faults.push(fault);
+}
+
+/// The fault handler can move the tape, so this must be reloaded after calling it
+fn load_tape_bounds(asm: &mut Assembler) {
+    dynasm!(asm
+        ; .arch aarch64
+        ; ldr x21, [x20, JitContext::TAPE_LO as u32]
+        ; ldr x22, [x20, JitContext::TAPE_HI as u32]
+    );
+}
+
+/// Puts the address of the cell at `offset` from the current one in `x{reg}`, clobbers `x4`
+fn cell_address(asm: &mut Assembler, reg: u32, offset: isize) {
+    let abs_offset = (offset as i64 * CELL_BYTES as i64).unsigned_abs();
+
+    match offset {
+        0 => dynasm!(asm
+            ; .arch aarch64
+            ; mov X(reg), x19
+        ),
+        1.. => {
+            load_imm(asm, 4, abs_offset);
+            dynasm!(asm
+                ; .arch aarch64
+                ; add X(reg), x19, x4
+            )
+        }
+        _ => {
+            load_imm(asm, 4, abs_offset);
+            dynasm!(asm
+                ; .arch aarch64
+                ; sub X(reg), x19, x4
+            )
+        }
+    }
+}
+
+/// Loads the cell at `x{addr}` into `w{reg}`, zero extended
+fn load(asm: &mut Assembler, width: CellWidth, reg: u32, addr: u32) {
+    match width {
+        CellWidth::U8 => dynasm!(asm
+            ; .arch aarch64
+            ; ldrb W(reg), [X(addr)]
+        ),
+        CellWidth::U16 => dynasm!(asm
+            ; .arch aarch64
+            ; ldrh W(reg), [X(addr)]
+        ),
+        CellWidth::U32 => dynasm!(asm
+            ; .arch aarch64
+            ; ldr W(reg), [X(addr)]
+        ),
+    }
+}
+
+/// Stores the low bits of `w{reg}` in the cell at `x{addr}`
+fn store(asm: &mut Assembler, width: CellWidth, reg: u32, addr: u32) {
+    match width {
+        CellWidth::U8 => dynasm!(asm
+            ; .arch aarch64
+            ; strb W(reg), [X(addr)]
+        ),
+        CellWidth::U16 => dynasm!(asm
+            ; .arch aarch64
+            ; strh W(reg), [X(addr)]
+        ),
+        CellWidth::U32 => dynasm!(asm
+            ; .arch aarch64
+            ; str W(reg), [X(addr)]
+        ),
+    }
+}
+
+/// Zeroes the current cell
+fn store_zero(asm: &mut Assembler, width: CellWidth) {
+    match width {
+        CellWidth::U8 => dynasm!(asm
+            ; .arch aarch64
+            ; strb wzr, [x19]
+        ),
+        CellWidth::U16 => dynasm!(asm
+            ; .arch aarch64
+            ; strh wzr, [x19]
+        ),
+        CellWidth::U32 => dynasm!(asm
+            ; .arch aarch64
+            ; str wzr, [x19]
+        ),
+    }
+}
+
+/// Brings the result of some arithmetic on a cell in `x{reg}` back into range when overflow
+/// doesn't wrap, jumping to `trap` instead if overflow traps, clobbers `x6`
+fn check_range(
+    asm: &mut Assembler,
+    width: CellWidth,
+    reg: u32,
+    overflow: Overflow,
+    trap: DynamicLabel,
+) {
+    load_imm(asm, 6, width.max().into());
+
+    // Negative results look huge when compared unsigned
+    match overflow {
+        Overflow::Trap => dynasm!(asm
+            ; .arch aarch64
+            ; cmp X(reg), x6
+            ; b.hi =>trap
+        ),
+        _ => dynasm!(asm
+            ; .arch aarch64
+            ; cmp XSP(reg), #0
+            ; csel X(reg), xzr, X(reg), lt
+            ; cmp X(reg), x6
+            ; csel X(reg), x6, X(reg), hi
+        ),
+    }
+}
+
+/// `mov` can only encode some immediates, so build larger ones up 16 bits at a time
+fn load_imm(asm: &mut Assembler, reg: u32, value: u64) {
+    dynasm!(asm
+        ; .arch aarch64
+        ; movz X(reg), (value & 0xFFFF) as u32
+    );
+
+    for shift in [16, 32, 48] {
+        let chunk = ((value >> shift) & 0xFFFF) as u32;
+
+        if chunk != 0 {
+            dynasm!(asm
+                ; .arch aarch64
+                ; movk X(reg), chunk, lsl shift
+            );
+        }
+    }
+}
@@ -0,0 +1,775 @@
+use std::collections::VecDeque;
+
+use anyhow::{Context, Result};
+use dynasmrt::{
+    dynasm, mmap::MutableBuffer, x64::X64Relocation, DynamicLabel, DynasmApi, DynasmLabelApi,
+    ExecutableBuffer, VecAssembler,
+};
+
+use super::{
+    move_extent, mul_add_extent, stationary_loop_accesses, Bound, CheckedRange, JitContext,
+    CELL_BYTES,
+};
+use crate::{
+    config::{CellWidth, Config, Overflow},
+    error::RuntimeError,
+    ir::Spanned,
+    lir::LirOp,
+};
+
+/// Code is assembled into a plain buffer, so it can be copied into executable memory or written
+/// out to a file alike
+pub(crate) type Assembler = VecAssembler<X64Relocation>;
+
+/// Register numbers for the dynamic registers we use
+const RAX: u8 = 0;
+const RCX: u8 = 1;
+const RDX: u8 = 2;
+
+/// Emits x86-64 code for `program`, ready to run
+pub fn gen(program: &[Spanned<LirOp>], config: &Config) -> Result<ExecutableBuffer> {
+    let mut asm = Assembler::new(0);
+    emit(&mut asm, program, config)?;
+
+    // Jumps are all relative, so the code can run from anywhere
+    let code = asm.finalize().expect("asm gen failed");
+    let mut buffer = MutableBuffer::new(code.len())?;
+    buffer.set_len(code.len());
+    buffer.copy_from_slice(&code);
+
+    Ok(buffer.make_exec()?)
+}
+
+/// Emits a function running `program` into `asm`, starting at its current offset
+///
+/// Follows the System V calling convention, so on entry `rdi` points to the first cell and `rsi` to
+/// the [`JitContext`]. These live in the callee-saved `rbx` and `r12` for the rest of the function
+/// so they survive calls into the runtime. The start and end of the tape are cached in `r13` and
+/// `r14` for bounds checks
+///
+/// Cells take up 4 bytes each, but only the low `width` bits are ever read or written
+pub(crate) fn emit(asm: &mut Assembler, program: &[Spanned<LirOp>], config: &Config) -> Result<()> {
+    let mut branch_table = VecDeque::new();
+
+    let width = config.cell_width;
+    let abort = asm.new_dynamic_label();
+    let mut checked = CheckedRange::default();
+    let mut faults = Vec::new();
+    let mut overflows = Vec::new();
+    let mut ticks = Vec::new();
+    let limited = config.is_limited();
+
+    // Five pushes leave the stack 16-byte aligned for calls
+    dynasm!(asm
+        ; .arch x64
+        ; push rbp
+        ; mov rbp, rsp
+        ; push rbx
+        ; push r12
+        ; push r13
+        ; push r14
+        ; mov rbx, rdi
+        ; mov r12, rsi
+    );
+
+    load_tape_bounds(asm);
+
+    for (i, op) in program.iter().enumerate() {
+        match &op.op {
+            LirOp::OffsetModify(delta, offset) => {
+                if let Some(bound) = checked.needs_check(*offset) {
+                    check_offset(asm, &mut faults, i, *offset, bound)?;
+                }
+
+                let disp = disp(*offset)?;
+
+                match config.cell_overflow {
+                    Overflow::Wrap => add_imm(asm, width, disp, *delta),
+                    overflow => {
+                        let label = asm.new_dynamic_label();
+                        overflows.push((label, i));
+
+                        add_checked(asm, width, disp, *delta, overflow, label);
+                    }
+                }
+            }
+            LirOp::Move(delta) => {
+                dynasm!(asm
+                    ; .arch x64
+                    ; add rbx, disp(*delta)?
+                );
+
+                if config.moves_are_exact() {
+                    // Check the whole stretch of tape we're about to use in one go
+                    checked.shift(*delta);
+
+                    let (lowest, highest) = move_extent(program, i);
+                    for (op, offset) in [lowest, highest] {
+                        if let Some(bound) = checked.needs_check(offset) {
+                            check_offset(asm, &mut faults, op, offset, bound)?;
+                        }
+                    }
+                } else if *delta != 0 {
+                    // The fault handler wraps or clamps the pointer back on to the tape, after
+                    // which all we know is that the current cell is on it
+                    let bound = if *delta > 0 {
+                        Bound::Upper
+                    } else {
+                        Bound::Lower
+                    };
+
+                    check_offset(asm, &mut faults, i, 0, bound)?;
+                    checked.reset();
+                }
+            }
+            LirOp::WriteZero => store_imm(asm, width, 0, 0),
+            LirOp::Hop(delta) => {
+                // Each hop starts from a cell on the tape, so can only fall off the end it's heading
+                let bound = if *delta > 0 {
+                    Bound::Upper
+                } else {
+                    Bound::Lower
+                };
+                let delta = disp(*delta)?;
+
+                let start = asm.new_dynamic_label();
+
+                cmp_zero(asm, width);
+                dynasm!(asm
+                    ; .arch x64
+                    ; je >end
+                    ; =>start
+                    ; add rbx, delta
+                );
+
+                check_offset(asm, &mut faults, i, 0, bound)?;
+                checked.reset();
+
+                branch_back(asm, width, limited.then_some(&mut ticks), i, start);
+                dynasm!(asm
+                    ; .arch x64
+                    ; end:
+                )
+            }
+            LirOp::MoveCell(delta) => {
+                // The target is only touched if the loop would have run, and can be off the tape
+                // if it wouldn't, so skip the whole op along with any check
+                cmp_zero(asm, width);
+                dynasm!(asm
+                    ; .arch x64
+                    ; je >skip
+                );
+
+                if let Some(bound) = checked.bound_for(*delta) {
+                    check_offset(asm, &mut faults, i, *delta, bound)?;
+                }
+
+                load(asm, width, 0, RAX);
+
+                match config.cell_overflow {
+                    Overflow::Wrap => add_reg(asm, width, disp(*delta)?, RAX),
+                    overflow => {
+                        let label = asm.new_dynamic_label();
+                        overflows.push((label, i));
+
+                        mul_add_checked(asm, width, disp(*delta)?, 1, overflow, label);
+                    }
+                }
+
+                store_imm(asm, width, 0, 0);
+                dynasm!(asm
+                    ; .arch x64
+                    ; skip:
+                )
+            }
+            LirOp::MulAdd(targets) => {
+                let (lowest, highest) = mul_add_extent(targets);
+
+                cmp_zero(asm, width);
+                dynasm!(asm
+                    ; .arch x64
+                    ; je >skip
+                );
+
+                // As with `MoveCell`, the targets are only checked if they will be touched
+                for offset in [lowest, highest] {
+                    if let Some(bound) = checked.bound_for(offset) {
+                        check_offset(asm, &mut faults, i, offset, bound)?;
+                    }
+                }
+
+                load(asm, width, 0, RAX);
+
+                let label = asm.new_dynamic_label();
+                if config.cell_overflow != Overflow::Wrap {
+                    overflows.push((label, i));
+                }
+
+                for &(offset, factor) in targets.iter() {
+                    let disp = disp(offset)?;
+
+                    if config.cell_overflow != Overflow::Wrap {
+                        let factor =
+                            i32::try_from(factor).context("Factor too large for the x86-64 JIT")?;
+
+                        mul_add_checked(asm, width, disp, factor, config.cell_overflow, label);
+                        continue;
+                    }
+
+                    // Only the low bits of the product end up in the cell
+                    match width.wrap(factor as u32) {
+                        1 => add_reg(asm, width, disp, RAX),
+                        factor if factor == width.max() => sub_reg(asm, width, disp, RAX),
+                        factor => {
+                            dynasm!(asm
+                                ; .arch x64
+                                ; imul ecx, eax, factor as i32
+                            );
+
+                            add_reg(asm, width, disp, RCX);
+                        }
+                    }
+                }
+
+                store_imm(asm, width, 0, 0);
+                dynasm!(asm
+                    ; .arch x64
+                    ; skip:
+                )
+            }
+            LirOp::In => {
+                dynasm!(asm
+                    ; .arch x64
+                    ; mov rdi, r12
+                    ; mov rsi, QWORD i as i64
+                    ; call QWORD [r12 + JitContext::READ]
+                    ; cmp rax, super::READ_UNCHANGED as i32
+                    ; je >done
+                    ; jl =>abort
+                );
+
+                store_reg(asm, width, 0, RAX);
+                dynasm!(asm
+                    ; .arch x64
+                    ; done:
+                )
+            }
+            LirOp::Out => dynasm!(asm
+                ; .arch x64
+                ; mov rax, QWORD [r12 + JitContext::OUT_LEN]
+                ; cmp rax, super::OUT_CAPACITY as i32
+                ; jb >store
+                ; mov rdi, r12
+                ; mov rsi, QWORD i as i64
+                ; call QWORD [r12 + JitContext::FLUSH]
+                ; test rax, rax
+                ; jnz =>abort
+                ; xor eax, eax
+                ; store:
+                // Output is the low byte of the cell, whatever its width
+                ; mov cl, BYTE [rbx]
+                ; mov BYTE [r12 + rax + JitContext::OUT_BUF], cl
+                ; add rax, 1
+                ; mov QWORD [r12 + JitContext::OUT_LEN], rax
+            ),
+            LirOp::BrFor => {
+                let back_branch = asm.new_dynamic_label();
+                let for_branch = asm.new_dynamic_label();
+
+                cmp_zero(asm, width);
+                dynasm!(asm
+                    ; .arch x64
+                    ; je =>for_branch
+                );
+
+                // What we know on exit is what we knew on entry, if the pointer can't have moved
+                let on_exit = match stationary_loop_accesses(program, i) {
+                    Some(accesses) => {
+                        let on_entry = checked;
+
+                        for (op, offset) in accesses {
+                            if let Some(bound) = checked.needs_check(offset) {
+                                check_offset(asm, &mut faults, op, offset, bound)?;
+                            }
+                        }
+
+                        on_entry
+                    }
+                    None => {
+                        checked.reset();
+                        CheckedRange::default()
+                    }
+                };
+
+                branch_table.push_back((op.span, for_branch, back_branch, on_exit));
+
+                dynasm!(asm
+                    ; .arch x64
+                    ; .align 16
+                    ; =>back_branch
+                );
+            }
+            LirOp::BrBack => {
+                let (_, for_branch, back_branch, on_exit) = branch_table
+                    .pop_back()
+                    .ok_or(RuntimeError::UnmatchedBracket { span: op.span })?;
+
+                branch_back(asm, width, limited.then_some(&mut ticks), i, back_branch);
+                dynasm!(asm
+                    ; .arch x64
+                    ; =>for_branch
+                );
+
+                checked = on_exit;
+            }
+            LirOp::Meta(_) => { /* meta nodes ignored */ }
+        }
+    }
+
+    // Only the innermost unclosed loop is reported, as `tree::build` does
+    if let Some(&(open, ..)) = branch_table.back() {
+        return Err(RuntimeError::UnmatchedBracket { span: open }.into());
+    }
+
+    dynasm!(asm
+        ; .arch x64
+        ; xor eax, eax
+        ; jmp >epilogue
+    );
+
+    // Failed bounds checks are rare, so keep them out of the way of the hot code
+    for fault in faults {
+        let offset = disp(fault.offset)?;
+
+        dynasm!(asm
+            ; .arch x64
+            ; =>fault.label
+            ; mov rdi, r12
+            ; mov rsi, rbx
+            ; lea rdx, [rbx + offset]
+            ; mov rcx, QWORD fault.op as i64
+            ; call QWORD [r12 + JitContext::FAULT]
+            ; test rax, rax
+            ; jz =>abort
+            ; mov rbx, rax
+        );
+
+        load_tape_bounds(asm);
+
+        dynasm!(asm
+            ; .arch x64
+            ; jmp =>fault.resume
+        );
+    }
+
+    for (label, op) in overflows {
+        dynasm!(asm
+            ; .arch x64
+            ; =>label
+            ; mov rdi, r12
+            ; mov rsi, QWORD op as i64
+            ; call QWORD [r12 + JitContext::OVERFLOW]
+            ; jmp =>abort
+        );
+    }
+
+    for tick in ticks {
+        dynasm!(asm
+            ; .arch x64
+            ; =>tick.label
+            ; mov rdi, r12
+            ; mov rsi, rbx
+            ; mov rdx, QWORD tick.op as i64
+            ; call QWORD [r12 + JitContext::TICK]
+            ; test rax, rax
+            ; jnz =>abort
+            ; jmp =>tick.resume
+        );
+    }
+
+    dynasm!(asm
+        ; .arch x64
+        ; =>abort
+        ; mov eax, 1
+        ; epilogue:
+        ; mov QWORD [r12 + JitContext::CELL], rbx
+        ; pop r14
+        ; pop r13
+        ; pop r12
+        ; pop rbx
+        ; pop rbp
+        ; ret
+    );
+
+    Ok(())
+}
+
+/// A failed bounds check, emitted after the body of the function
+struct Fault {
+    label: DynamicLabel,
+    resume: DynamicLabel,
+    op: usize,
+    offset: isize,
+}
+
+/// A loop which has used up the iterations it was given, emitted after the body of the function
+struct Tick {
+    label: DynamicLabel,
+    resume: DynamicLabel,
+    op: usize,
+}
+
+/// Jumps back to the start of a loop at `target` if the current cell is non-zero
+///
+/// If the program has limits, `ticks` is given and each iteration is counted, jumping to a stub
+/// which calls the runtime once the loop has used up the iterations it was given
+fn branch_back(
+    asm: &mut Assembler,
+    width: CellWidth,
+    ticks: Option<&mut Vec<Tick>>,
+    op: usize,
+    target: DynamicLabel,
+) {
+    cmp_zero(asm, width);
+
+    let Some(ticks) = ticks else {
+        dynasm!(asm
+            ; .arch x64
+            ; jne =>target
+        );
+
+        return;
+    };
+
+    let tick = Tick {
+        label: asm.new_dynamic_label(),
+        resume: target,
+        op,
+    };
+
+    dynasm!(asm
+        ; .arch x64
+        ; je >done
+        ; sub QWORD [r12 + JitContext::TICKS], 1
+        ; jnz =>target
+        ; jmp =>tick.label
+        ; done:
+    );
+
+    ticks.push(tick);
+}
+
+/// Checks the cell at `offset` from the current one is within `bound`, jumping to a stub which
+/// calls the fault handler if it isn't
+fn check_offset(
+    asm: &mut Assembler,
+    faults: &mut Vec<Fault>,
+    op: usize,
+    offset: isize,
+    bound: Bound,
+) -> Result<()> {
+    let fault = Fault {
+        label: asm.new_dynamic_label(),
+        resume: asm.new_dynamic_label(),
+        op,
+        offset,
+    };
+
+    let offset = disp(offset)?;
+
+    match (bound, offset) {
+        (Bound::Lower, 0) => dynasm!(asm
+            ; .arch x64
+            ; cmp rbx, r13
+            ; jb =>fault.label
+        ),
+        (Bound::Upper, 0) => dynasm!(asm
+            ; .arch x64
+            ; cmp rbx, r14
+            ; jae =>fault.label
+        ),
+        (Bound::Lower, _) => dynasm!(asm
+            ; .arch x64
+            ; lea rax, [rbx + offset]
+            ; cmp rax, r13
+            ; jb =>fault.label
+        ),
+        (Bound::Upper, _) => dynasm!(asm
+            ; .arch x64
+            ; lea rax, [rbx + offset]
+            ; cmp rax, r14
+            ; jae =>fault.label
+        ),
+    }
+
+    dynasm!(asm
+        ; .arch x64
+        ; =>fault.resume
+    );
+
+    faults.push(fault);
+
+    Ok(())
+}
+
+/// The fault handler can move the tape, so this must be reloaded after calling it
+fn load_tape_bounds(asm: &mut Assembler) {
+    dynasm!(asm
+        ; .arch x64
+        ; mov r13, QWORD [r12 + JitContext::TAPE_LO]
+        ; mov r14, QWORD [r12 + JitContext::TAPE_HI]
+    );
+}
+
+/// The displacement in bytes of the cell `offset` cells away from the current one
+///
+/// x86-64 only has 32-bit immediates and displacements for the instructions we use
+fn disp(offset: isize) -> Result<i32> {
+    offset
+        .checked_mul(CELL_BYTES)
+        .and_then(|disp| i32::try_from(disp).ok())
+        .context("Offset too large for the x86-64 JIT")
+}
+
+/// Sets the flags for comparing the current cell against zero
+fn cmp_zero(asm: &mut Assembler, width: CellWidth) {
+    match width {
+        CellWidth::U8 => dynasm!(asm
+            ; .arch x64
+            ; cmp BYTE [rbx], 0
+        ),
+        CellWidth::U16 => dynasm!(asm
+            ; .arch x64
+            ; cmp WORD [rbx], 0
+        ),
+        CellWidth::U32 => dynasm!(asm
+            ; .arch x64
+            ; cmp DWORD [rbx], 0
+        ),
+    }
+}
+
+/// Stores `value` in the cell at `disp`
+fn store_imm(asm: &mut Assembler, width: CellWidth, disp: i32, value: u32) {
+    match width {
+        CellWidth::U8 => dynasm!(asm
+            ; .arch x64
+            ; mov BYTE [rbx + disp], value as i8
+        ),
+        CellWidth::U16 => dynasm!(asm
+            ; .arch x64
+            ; mov WORD [rbx + disp], value as i16
+        ),
+        CellWidth::U32 => dynasm!(asm
+            ; .arch x64
+            ; mov DWORD [rbx + disp], value as i32
+        ),
+    }
+}
+
+/// Loads the cell at `disp` into `reg`, zero extended
+fn load(asm: &mut Assembler, width: CellWidth, disp: i32, reg: u8) {
+    match width {
+        CellWidth::U8 => dynasm!(asm
+            ; .arch x64
+            ; movzx Rd(reg), BYTE [rbx + disp]
+        ),
+        CellWidth::U16 => dynasm!(asm
+            ; .arch x64
+            ; movzx Rd(reg), WORD [rbx + disp]
+        ),
+        CellWidth::U32 => dynasm!(asm
+            ; .arch x64
+            ; mov Rd(reg), DWORD [rbx + disp]
+        ),
+    }
+}
+
+/// Stores the low bits of `reg` in the cell at `disp`
+fn store_reg(asm: &mut Assembler, width: CellWidth, disp: i32, reg: u8) {
+    match width {
+        CellWidth::U8 => dynasm!(asm
+            ; .arch x64
+            ; mov BYTE [rbx + disp], Rb(reg)
+        ),
+        CellWidth::U16 => dynasm!(asm
+            ; .arch x64
+            ; mov WORD [rbx + disp], Rw(reg)
+        ),
+        CellWidth::U32 => dynasm!(asm
+            ; .arch x64
+            ; mov DWORD [rbx + disp], Rd(reg)
+        ),
+    }
+}
+
+/// Adds `delta` to the cell at `disp`, only the low bits of it matter
+fn add_imm(asm: &mut Assembler, width: CellWidth, disp: i32, delta: isize) {
+    match width {
+        CellWidth::U8 => dynasm!(asm
+            ; .arch x64
+            ; add BYTE [rbx + disp], delta as i8
+        ),
+        CellWidth::U16 => dynasm!(asm
+            ; .arch x64
+            ; add WORD [rbx + disp], delta as i16
+        ),
+        CellWidth::U32 => dynasm!(asm
+            ; .arch x64
+            ; add DWORD [rbx + disp], delta as i32
+        ),
+    }
+}
+
+/// Subtracts `delta` from the cell at `disp`, only the low bits of it matter
+fn sub_imm(asm: &mut Assembler, width: CellWidth, disp: i32, delta: isize) {
+    match width {
+        CellWidth::U8 => dynasm!(asm
+            ; .arch x64
+            ; sub BYTE [rbx + disp], delta as i8
+        ),
+        CellWidth::U16 => dynasm!(asm
+            ; .arch x64
+            ; sub WORD [rbx + disp], delta as i16
+        ),
+        CellWidth::U32 => dynasm!(asm
+            ; .arch x64
+            ; sub DWORD [rbx + disp], delta as i32
+        ),
+    }
+}
+
+/// Adds `delta` to the cell at `disp` when overflow doesn't wrap, jumping to `trap` if it
+/// overflows and overflow traps
+fn add_checked(
+    asm: &mut Assembler,
+    width: CellWidth,
+    disp: i32,
+    delta: isize,
+    overflow: Overflow,
+    trap: DynamicLabel,
+) {
+    // Where the cell ends up if it saturates
+    let end = if delta > 0 { width.max() } else { 0 };
+
+    if delta.unsigned_abs() > width.max() as usize {
+        // Overflows whatever the cell holds
+        match overflow {
+            Overflow::Trap => dynasm!(asm
+                ; .arch x64
+                ; jmp =>trap
+            ),
+            _ => store_imm(asm, width, disp, end),
+        }
+
+        return;
+    }
+
+    // Unsigned adds and subtracts set the carry flag when they go past either end
+    if delta > 0 {
+        add_imm(asm, width, disp, delta);
+    } else {
+        sub_imm(asm, width, disp, -delta);
+    }
+
+    match overflow {
+        Overflow::Trap => dynasm!(asm
+            ; .arch x64
+            ; jc =>trap
+        ),
+        _ => {
+            dynasm!(asm
+                ; .arch x64
+                ; jnc >in_range
+            );
+
+            store_imm(asm, width, disp, end);
+
+            dynasm!(asm
+                ; .arch x64
+                ; in_range:
+            );
+        }
+    }
+}
+
+/// Adds `factor` times `rax` to the cell at `disp` when overflow doesn't wrap, jumping to `trap`
+/// if it overflows and overflow traps
+///
+/// Works in 64 bits, which no cell and factor can overflow, before checking the result
+fn mul_add_checked(
+    asm: &mut Assembler,
+    width: CellWidth,
+    disp: i32,
+    factor: i32,
+    overflow: Overflow,
+    trap: DynamicLabel,
+) {
+    dynasm!(asm
+        ; .arch x64
+        ; imul rcx, rax, factor
+    );
+
+    load(asm, width, disp, RDX);
+
+    dynasm!(asm
+        ; .arch x64
+        ; add rdx, rcx
+        ; mov r8d, width.max() as i32
+    );
+
+    // Negative results look huge when compared unsigned
+    match overflow {
+        Overflow::Trap => dynasm!(asm
+            ; .arch x64
+            ; cmp rdx, r8
+            ; ja =>trap
+        ),
+        _ => dynasm!(asm
+            ; .arch x64
+            ; xor r9d, r9d
+            ; test rdx, rdx
+            ; cmovs rdx, r9
+            ; cmp rdx, r8
+            ; cmova rdx, r8
+        ),
+    }
+
+    store_reg(asm, width, disp, RDX);
+}
+
+/// Adds the low bits of `reg` to the cell at `disp`
+fn add_reg(asm: &mut Assembler, width: CellWidth, disp: i32, reg: u8) {
+    match width {
+        CellWidth::U8 => dynasm!(asm
+            ; .arch x64
+            ; add BYTE [rbx + disp], Rb(reg)
+        ),
+        CellWidth::U16 => dynasm!(asm
+            ; .arch x64
+            ; add WORD [rbx + disp], Rw(reg)
+        ),
+        CellWidth::U32 => dynasm!(asm
+            ; .arch x64
+            ; add DWORD [rbx + disp], Rd(reg)
+        ),
+    }
+}
+
+/// Subtracts the low bits of `reg` from the cell at `disp`
+fn sub_reg(asm: &mut Assembler, width: CellWidth, disp: i32, reg: u8) {
+    match width {
+        CellWidth::U8 => dynasm!(asm
+            ; .arch x64
+            ; sub BYTE [rbx + disp], Rb(reg)
+        ),
+        CellWidth::U16 => dynasm!(asm
+            ; .arch x64
+            ; sub WORD [rbx + disp], Rw(reg)
+        ),
+        CellWidth::U32 => dynasm!(asm
+            ; .arch x64
+            ; sub DWORD [rbx + disp], Rd(reg)
+        ),
+    }
+}
@@ -0,0 +1,66 @@
+//! A brainfuck JIT and interpreter set
+//!
+//! [`Program`] is the simplest way to embed rustfuck: compile some source for one of the backends,
+//! then run it against your own tape and I/O as many times as you like. Each backend implements
+//! [`Backend`], and the individual stages are available in the other modules for finer control
+
+use std::io::{Read, Write};
+
+use anyhow::Result;
+use bumpalo::Bump;
+
+pub use crate::{
+    backend::{Backend, BackendKind},
+    config::Config,
+    error::RuntimeError,
+};
+use crate::{parser::BfParser, state::BrainfuckState};
+
+pub mod aot;
+pub mod backend;
+pub mod config;
+pub mod emit;
+pub mod error;
+pub mod hir;
+pub mod ir;
+pub mod jit;
+pub mod limit;
+pub mod lir;
+pub mod parser;
+pub mod state;
+pub mod tree;
+
+/// A program compiled for a particular backend, ready to run
+///
+/// Any data the optimised program needs lives in the arena it was compiled with
+pub struct Program<'a> {
+    backend: Box<dyn Backend<'a> + 'a>,
+}
+
+impl<'a> Program<'a> {
+    /// Parses and compiles `source` for `backend`
+    ///
+    /// Fails if the source is malformed, or the backend isn't supported on this machine
+    pub fn compile(
+        source: &[u8],
+        backend: BackendKind,
+        config: &Config,
+        arena: &'a Bump,
+    ) -> Result<Self> {
+        let parsed = BfParser::parse(source)?;
+
+        Ok(Self {
+            backend: backend.compile(&parsed, config, arena)?,
+        })
+    }
+
+    /// Runs the program starting from `state`, leaving it as the program left it
+    pub fn run(
+        &self,
+        state: &mut BrainfuckState,
+        input: &mut dyn Read,
+        output: &mut dyn Write,
+    ) -> Result<()> {
+        self.backend.run(state, input, output)
+    }
+}
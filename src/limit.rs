@@ -0,0 +1,127 @@
+use std::{
+    error::Error,
+    fmt::{self, Display},
+    mem,
+    time::{Duration, Instant},
+};
+
+use crate::config::Config;
+use crate::ir::Span;
+use crate::state::BrainfuckState;
+
+/// How many steps are taken between looking at the clock, as doing so every step is slow
+const CLOCK_INTERVAL: u64 = 1 << 16;
+
+/// One of the limits in a [`Config`]
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum Limit {
+    /// The program took this many steps
+    Steps(u64),
+    /// The program ran for this long
+    Time(Duration),
+}
+
+impl Display for Limit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Limit::Steps(steps) => write!(f, "Step limit of {steps}"),
+            Limit::Time(time) => write!(f, "Time limit of {time:?}"),
+        }
+    }
+}
+
+/// A program was stopped for running into one of its limits
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub struct LimitExceeded {
+    pub limit: Limit,
+    /// The instruction which was about to run
+    pub span: Span,
+    /// Where the pointer was, relative to the first cell of the tape
+    pub pointer: isize,
+}
+
+impl Display for LimitExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} exceeded at {}, with the pointer at cell {}",
+            self.limit, self.span, self.pointer
+        )
+    }
+}
+
+impl Error for LimitExceeded {}
+
+/// Keeps track of how much of its limits a running program has used
+///
+/// What a step is depends on the backend: the interpreters count every op they run, while jitted
+/// code only counts loop iterations
+#[derive(Debug, Clone)]
+pub struct Budget {
+    /// Steps which can be taken before the limits need looking at again
+    remaining: u64,
+    /// Steps handed out so far, including `remaining`
+    granted: u64,
+    steps: Option<u64>,
+    deadline: Option<(Instant, Duration)>,
+}
+
+impl Budget {
+    /// Starts counting against the limits in `config`, the clock starts now
+    pub fn new(config: &Config) -> Self {
+        Self {
+            remaining: 0,
+            granted: 0,
+            steps: config.step_limit,
+            deadline: config
+                .time_limit
+                .map(|limit| (Instant::now() + limit, limit)),
+        }
+    }
+
+    /// Takes a step, failing if it would go over a limit
+    ///
+    /// `span` and `state` are only used for the error
+    #[inline]
+    pub fn step(&mut self, span: Span, state: &BrainfuckState) -> Result<(), LimitExceeded> {
+        match self.remaining.checked_sub(1) {
+            Some(remaining) => {
+                self.remaining = remaining;
+                Ok(())
+            }
+            None => self.refill().map_err(|limit| LimitExceeded {
+                limit,
+                span,
+                pointer: state.pointer(),
+            }),
+        }
+    }
+
+    /// Takes away every step which can be taken before the limits need looking at again, for code
+    /// which keeps count itself and calls [`Budget::step`] once it runs out
+    pub fn take_remaining(&mut self) -> u64 {
+        mem::take(&mut self.remaining)
+    }
+
+    /// Checks the limits once the steps handed out are used up, then hands out some more and takes
+    /// one of them
+    #[cold]
+    fn refill(&mut self) -> Result<(), Limit> {
+        if let Some((deadline, limit)) = self.deadline {
+            if Instant::now() >= deadline {
+                return Err(Limit::Time(limit));
+            }
+        }
+
+        let batch = match self.steps {
+            Some(steps) if self.granted >= steps => return Err(Limit::Steps(steps)),
+            Some(steps) => CLOCK_INTERVAL.min(steps - self.granted),
+            None => CLOCK_INTERVAL,
+        };
+
+        self.granted += batch;
+        self.remaining = batch - 1;
+
+        Ok(())
+    }
+}
@@ -1,13 +1,26 @@
 use std::{
-    collections::HashMap,
-    io::{self, Read, Write},
+    collections::{BTreeMap, HashMap},
+    io::{Read, Write},
+    mem,
 };
 
 use anyhow::Result;
+use bumpalo::Bump;
 use log::{info, trace};
 use tap::prelude::*;
 
-use crate::{hir::HirOp, ir::IrLike, state::BrainfuckState};
+use crate::{
+    backend::Backend,
+    config::{Config, Overflow, TapeMode},
+    error::RuntimeError,
+    hir::{BfOp, HirGen, HirOp},
+    ir::{gen_branch_table, IrLike, LoopMarkers, Span, Spanned},
+    limit::Budget,
+    state::BrainfuckState,
+    tree::{self, Loop, Node},
+};
+
+type LirNode<'a> = Node<'a, LirOp<'a>>;
 
 #[derive(Debug, PartialEq, Eq, Copy, Clone)]
 pub enum LirOp<'a> {
@@ -20,6 +33,11 @@ pub enum LirOp<'a> {
     Hop(isize),      // Moves +/- in hops of n until it finds a non-zero cell
     MoveCell(isize), // Adds the content of the current cell to another cell
 
+    // For each (offset, factor), adds factor * the current cell to the cell at offset, then zeroes
+    // the current cell. This is what a loop which only modifies cells and decrements its counter
+    // by one boils down to
+    MulAdd(&'a [(/* offset */ isize, /* factor */ isize)]),
+
     // A simple loop which has an overall offset of 0
     In,
     Out,
@@ -31,6 +49,11 @@ pub enum LirOp<'a> {
     Meta(&'a str),
 }
 
+impl LoopMarkers for LirOp<'_> {
+    const BR_FOR: Self = LirOp::BrFor;
+    const BR_BACK: Self = LirOp::BrBack;
+}
+
 impl IrLike for LirOp<'_> {
     fn to_compact(&self) -> String {
         match self {
@@ -47,6 +70,14 @@ impl IrLike for LirOp<'_> {
             LirOp::WriteZero => "Zero".into(),
             LirOp::Hop(mov_delta) => format!("Hop({mov_delta})"),
             LirOp::MoveCell(delta) => format!("MovCell({delta})"),
+            LirOp::MulAdd(targets) => {
+                let targets = targets
+                    .iter()
+                    .map(|(offset, factor)| format!("{offset}: *{factor}"))
+                    .collect::<Vec<_>>();
+
+                format!("MulAdd({})", targets.join(", "))
+            }
             LirOp::Meta(comment) => format!("<{comment}>"),
         }
     }
@@ -55,96 +86,171 @@ impl IrLike for LirOp<'_> {
 pub struct LirGen;
 
 impl LirGen {
-    pub fn gen_ir(hir: &[HirOp]) -> Vec<LirOp> {
+    /// Lowers `hir`, with any data the ops point to allocated in `arena`
+    ///
+    /// Only optimisations which can't change what happens on overflow under `config` are made.
+    /// Fails if the loops in `hir` aren't balanced
+    pub fn gen_ir<'a>(
+        hir: &[Spanned<HirOp>],
+        config: &Config,
+        arena: &'a Bump,
+    ) -> Result<Vec<Spanned<LirOp<'a>>>, RuntimeError> {
         info!("Starting LIR gen");
 
-        let mut pos = 0;
+        let hir = tree::build(hir, arena)?;
 
-        let mut lir = Vec::new();
+        let lir = Self::lower_hir(hir, config, arena);
 
-        while let Some(op) = hir.get(pos) {
-            // Any combo like [-], [+], [++++] is a set-to-zero
-            // (not really, but its either that or an infinite loop and we will simply ignore infinite loops)
-
-            let lir_op = match op {
-                HirOp::Modify(delta) => LirOp::OffsetModify(*delta, 0),
-                HirOp::Move(delta) => LirOp::Move(*delta),
-                HirOp::In => LirOp::In,
-                HirOp::Out => LirOp::Out,
-                HirOp::BrFor => {
-                    if let Some((opt, skip)) = Self::try_opt_simple_hir_loop(&hir[pos..]) {
-                        trace!("applied HIR loop-opt {opt:?} (was {:?})", &opt);
-                        pos += skip;
-                        opt
-                    } else {
-                        LirOp::BrFor
-                    }
-                }
-                HirOp::BrBack => LirOp::BrBack,
-            };
+        // Second LIR pass
+        let lir = Self::opt_loops(lir, config, arena);
 
-            lir.push(lir_op);
-            pos += 1;
-        }
+        Ok(tree::lower(lir))
+    }
 
-        // Second LIR pass
+    /// Whether moves can be folded into the offsets of the ops between them
+    ///
+    /// Each op checks the cells it touches are on the tape, which is only equivalent to moving
+    /// there when the pointer never wraps or clamps. On a fixed tape, every cell the pointer stops
+    /// at must still be touched, so there can't be back-to-back moves
+    fn can_fold_moves(body: &[Spanned<LirOp>], config: &Config) -> bool {
+        let back_to_back = body
+            .windows(2)
+            .any(|ops| matches!((ops[0].op, ops[1].op), (LirOp::Move(_), LirOp::Move(_))));
+
+        config.tape == TapeMode::Infinite
+            || config.pointer_overflow == Overflow::Trap && !back_to_back
+    }
 
-        let mut lir2 = Vec::new();
-        let mut pos = 0;
+    fn lower_hir<'a>(hir: &[Node<HirOp>], config: &Config, arena: &'a Bump) -> &'a [LirNode<'a>] {
+        // Loops can nest deeper than the call stack would allow, so keep our own. Each frame is a
+        // loop being lowered, with what is left of the body around it and what that has been
+        // lowered to so far
+        let mut stack = Vec::new();
+        let mut body = hir.iter();
+        let mut lir = Vec::new();
 
-        while let Some(op) = lir.get(pos) {
-            // TODO: make less-allocy (vecs)
-            let lir2_ops = match op {
-                LirOp::BrFor => {
-                    if let Some((opts, skip)) = Self::try_opt_simple_lir_loop(&lir[pos..]) {
-                        trace!("applied LIR loop-opt {:?}", &opts);
-                        pos += skip;
-                        opts
-                    } else {
-                        vec![LirOp::BrFor]
-                    }
-                }
-                op =>
-                /* clone is cheap as we don't have any CnstMovSets yet */
-                {
-                    vec![*op]
+        loop {
+            match body.next() {
+                Some(Node::Op(Spanned { op, span })) => {
+                    let lir_op = match op {
+                        HirOp::Modify(delta) => LirOp::OffsetModify(*delta, 0),
+                        HirOp::Move(delta) => LirOp::Move(*delta),
+                        HirOp::In => LirOp::In,
+                        HirOp::Out => LirOp::Out,
+                        HirOp::BrFor | HirOp::BrBack => unreachable!("loops are nodes in the tree"),
+                    };
+
+                    lir.push(Node::Op(Spanned::new(lir_op, *span)));
                 }
-            };
+                Some(Node::Loop(lp)) => match Self::try_opt_simple_hir_loop(lp, config) {
+                    Some(opt) => {
+                        trace!("applied HIR loop-opt {opt:?}");
 
-            lir2.extend(lir2_ops);
-            pos += 1;
+                        // A fused loop covers everything from its `[` to its `]`
+                        lir.push(Node::Op(Spanned::new(opt, lp.span())));
+                    }
+                    None => stack.push((
+                        lp,
+                        mem::replace(&mut body, lp.body.iter()),
+                        mem::take(&mut lir),
+                    )),
+                },
+                None => {
+                    let Some((lp, outer, outer_lir)) = stack.pop() else {
+                        break;
+                    };
+
+                    let lowered = arena.alloc_slice_copy(&mem::replace(&mut lir, outer_lir));
+                    lir.push(Node::Loop(Loop {
+                        open: lp.open,
+                        close: lp.close,
+                        body: lowered,
+                    }));
+
+                    body = outer;
+                }
+            }
         }
 
-        lir2
+        arena.alloc_slice_copy(&lir)
     }
 
-    /// A simple loop is one with no nested loops
-    fn try_opt_simple_hir_loop(hir: &[HirOp]) -> Option<(LirOp, usize)> {
-        let loop_end = hir[1..]
-            .iter()
-            .position(|&op| matches!(op, HirOp::BrFor | HirOp::BrBack))
-            .map(|v| /* account for skipping first br */ v + 1);
+    /// Optimises every loop in `lir`, innermost first, so that once an inner loop has become a
+    /// straight-line op the loop around it can be optimised too
+    fn opt_loops<'a>(lir: &[LirNode<'a>], config: &Config, arena: &'a Bump) -> &'a [LirNode<'a>] {
+        // As with lowering, each frame is a loop being optimised, with what is left of the body
+        // around it and what that has been optimised to so far
+        let mut stack = Vec::new();
+        let mut body = lir.iter();
+        let mut opt = Vec::new();
+
+        loop {
+            match body.next() {
+                Some(Node::Op(op)) => opt.push(Node::Op(*op)),
+                Some(Node::Loop(lp)) => stack.push((
+                    lp,
+                    mem::replace(&mut body, lp.body.iter()),
+                    mem::take(&mut opt),
+                )),
+                None => {
+                    let Some((lp, outer, outer_opt)) = stack.pop() else {
+                        break;
+                    };
+
+                    let inner = arena.alloc_slice_copy(&mem::replace(&mut opt, outer_opt));
+                    opt.push(Self::opt_loop(lp.with_body(inner), config, arena));
+
+                    body = outer;
+                }
+            }
+        }
 
-        let loop_end = match loop_end {
-            None => return None,
-            Some(loop_end) if hir[loop_end] == HirOp::BrFor /* nested loop, not simple */ => return None,
-            Some(loop_end) => loop_end,
-        };
+        arena.alloc_slice_copy(&opt)
+    }
+
+    /// Optimises a loop whose body has already been optimised
+    fn opt_loop<'a>(lp: Loop<'a, LirOp<'a>>, config: &Config, arena: &'a Bump) -> LirNode<'a> {
+        // These do nothing on a zero cell and always leave one behind, so a loop around one runs at
+        // most once and can be dropped, e.g. `[[-]]` or `[[->+<]]`
+        if let [Node::Op(Spanned {
+            op: op @ (LirOp::WriteZero | LirOp::Hop(_) | LirOp::MoveCell(_) | LirOp::MulAdd(_)),
+            ..
+        })] = lp.body
+        {
+            trace!("applied nested loop-opt {op:?}");
+            return Node::Op(Spanned::new(*op, lp.span()));
+        }
 
-        assert_eq!(hir[0], HirOp::BrFor);
-        assert_eq!(hir[loop_end], HirOp::BrBack);
+        match Self::try_opt_simple_lir_loop(&lp, config, arena)
+            .or_else(|| Self::try_opt_clearing_loop(&lp, config, arena))
+        {
+            Some(opt) => {
+                trace!("applied LIR loop-opt {:?}", opt);
+                opt
+            }
+            None => Node::Loop(lp),
+        }
+    }
 
-        let loop_content = &hir[1..loop_end];
+    /// A simple loop is one with no nested loops
+    fn try_opt_simple_hir_loop(lp: &Loop<HirOp>, config: &Config) -> Option<LirOp<'static>> {
+        let loop_content = lp.simple_body()?;
+        let loop_content = loop_content.iter().map(|op| op.op).collect::<Vec<_>>();
 
         trace!("attempting HIR loop-opt for {loop_content:?}");
 
-        match loop_content {
-            [HirOp::Modify(_)] => Some((LirOp::WriteZero, 2)),
-            [HirOp::Move(delta)] => Some((LirOp::Hop(*delta), 2)),
+        // Any combo like [-], [+], [++++] is a set-to-zero
+        // (not really, but its either that or an infinite loop and we will simply ignore infinite loops)
+        // Unless cells wrap, only [-] is certain to stop at zero rather than overflow
+        match loop_content[..] {
+            [HirOp::Modify(delta)] if config.cell_overflow == Overflow::Wrap || delta == -1 => {
+                Some(LirOp::WriteZero)
+            }
+            [HirOp::Move(delta)] => Some(LirOp::Hop(delta)),
             [HirOp::Modify(-1), HirOp::Move(delta), HirOp::Modify(1), HirOp::Move(ndelta)]
-                if *delta == -ndelta =>
+                if delta == -ndelta && config.moves_are_exact() =>
             {
-                Some((LirOp::MoveCell(*delta), 5))
+                Some(LirOp::MoveCell(delta))
             }
             _ => {
                 trace!("missed HIR loop-opt for {:?}", loop_content.to_compact());
@@ -153,28 +259,20 @@ impl LirGen {
         }
     }
 
-    fn try_opt_simple_lir_loop<'a>(lir: &[LirOp<'a>]) -> Option<(Vec<LirOp<'a>>, usize)> {
-        let loop_end = lir[1..]
-            .iter()
-            .position(|op| matches!(op, LirOp::BrFor | LirOp::BrBack))
-            .map(|v| /* account for skipping first br */ v + 1);
-
-        let loop_end = match loop_end {
-            None => return None,
-            Some(loop_end) if lir[loop_end] == LirOp::BrFor /* nested loop, not simple */ => return None,
-            Some(loop_end) => loop_end,
-        };
-
-        assert_eq!(lir[0], LirOp::BrFor);
-        assert_eq!(lir[loop_end], LirOp::BrBack);
-
-        let loop_content = &lir[1..loop_end];
+    /// A simple loop is one with no nested loops
+    fn try_opt_simple_lir_loop<'a>(
+        lp: &Loop<'a, LirOp<'a>>,
+        config: &Config,
+        arena: &'a Bump,
+    ) -> Option<LirNode<'a>> {
+        let loop_content = lp.simple_body()?;
 
         trace!("attempting LIR loop-opt for {loop_content:?}");
 
         if loop_content
             .iter()
-            .all(|op| matches!(op, LirOp::OffsetModify(_, 0) | LirOp::Move(_)))
+            .all(|op| matches!(op.op, LirOp::OffsetModify(_, 0) | LirOp::Move(_)))
+            && Self::can_fold_moves(&loop_content, config)
         {
             // mod/mov chain
             // we can transform this into a special node
@@ -182,75 +280,252 @@ impl LirGen {
             let mut set = Vec::new();
 
             let mut offset = 0isize;
-            for op in loop_content {
-                match op {
+            for op in &loop_content {
+                match op.op {
                     LirOp::Move(delta) => offset += delta,
-                    LirOp::OffsetModify(delta, 0) => set.push(LirOp::OffsetModify(*delta, offset)),
+                    LirOp::OffsetModify(delta, 0) => {
+                        set.push(Spanned::new(LirOp::OffsetModify(delta, offset), op.span))
+                    }
                     _ => unreachable!(),
                 }
             }
 
-            // Key point - we must insert a mov to ensure we remain in the same location at the end
-            let fixup_mov = LirOp::Move(offset);
+            if let Some(targets) = Self::try_mul_add(&set, offset, config) {
+                let mul_add = match targets[..] {
+                    [(offset, 1)] => LirOp::MoveCell(offset),
+                    _ => LirOp::MulAdd(arena.alloc_slice_copy(&targets)),
+                };
 
-            let mut new_ops = vec![LirOp::BrFor];
-            new_ops.extend(set);
+                return Some(Node::Op(Spanned::new(mul_add, lp.span())));
+            }
+
+            let mut new_body = set.into_iter().map(Node::Op).collect::<Vec<_>>();
 
+            // Key point - we must insert a mov to ensure we remain in the same location at the end
+            // It stands in for all the moves in the loop, so covers the whole body
             if offset != 0 {
-                new_ops.push(fixup_mov);
-            }
+                let body_span = loop_content[0]
+                    .span
+                    .to(loop_content[loop_content.len() - 1].span);
 
-            new_ops.push(LirOp::BrBack);
+                new_body.push(Node::Op(Spanned::new(LirOp::Move(offset), body_span)));
+            }
 
-            Some((new_ops, loop_content.len() + 1))
+            Some(Node::Loop(lp.with_body(arena.alloc_slice_copy(&new_body))))
         } else {
             trace!("missed LIR loop-opt for {:?}", loop_content.to_compact());
             None
         }
     }
+
+    /// A simple loop which clears some cells as well as modifying others, such as one around an
+    /// already optimised `[-]` like `[>[-]<-]`
+    ///
+    /// Every iteration leaves a cleared cell the same way, so if the rest of the loop is a
+    /// multiply-add, it only needs to run once: clearing and modifying those cells, then doing the
+    /// multiply-add, which zeroes the counter
+    fn try_opt_clearing_loop<'a>(
+        lp: &Loop<'a, LirOp<'a>>,
+        config: &Config,
+        arena: &'a Bump,
+    ) -> Option<LirNode<'a>> {
+        let loop_content = lp.simple_body()?;
+
+        if !loop_content.iter().all(|op| {
+            matches!(
+                op.op,
+                LirOp::OffsetModify(_, 0) | LirOp::Move(_) | LirOp::WriteZero
+            )
+        }) || !Self::can_fold_moves(&loop_content, config)
+        {
+            return None;
+        }
+
+        // The clear of each cell which has one, along with whatever is done to it after the last
+        let mut cleared = BTreeMap::new();
+        let mut set = Vec::new();
+
+        let mut offset = 0isize;
+        for op in &loop_content {
+            match op.op {
+                LirOp::Move(delta) => offset += delta,
+                LirOp::WriteZero => {
+                    cleared.insert(offset, (op.span, Vec::new()));
+                }
+                LirOp::OffsetModify(delta, 0) => match cleared.get_mut(&offset) {
+                    Some((_, after)) => {
+                        after.push(Spanned::new(LirOp::OffsetModify(delta, 0), op.span))
+                    }
+                    None => set.push(Spanned::new(LirOp::OffsetModify(delta, offset), op.span)),
+                },
+                _ => unreachable!(),
+            }
+        }
+
+        // The counter can't be cleared, as the multiply-add relies on it counting down
+        if cleared.is_empty() || cleared.contains_key(&0) {
+            return None;
+        }
+
+        // Modifying a cell before it is cleared does nothing, so those can be dropped, unless they
+        // could overflow first
+        let (before_clear, set): (Vec<_>, Vec<_>) = set.into_iter().partition(
+            |op| matches!(op.op, LirOp::OffsetModify(_, offset) if cleared.contains_key(&offset)),
+        );
+        if !before_clear.is_empty() && config.cell_overflow != Overflow::Wrap {
+            return None;
+        }
+
+        let targets = Self::try_mul_add(&set, offset, config)?;
+
+        let mut new_body = Vec::new();
+
+        let mut offset = 0isize;
+        for (&target, (span, after)) in &cleared {
+            new_body.push(Node::Op(Spanned::new(LirOp::Move(target - offset), *span)));
+            new_body.push(Node::Op(Spanned::new(LirOp::WriteZero, *span)));
+            new_body.extend(after.iter().copied().map(Node::Op));
+
+            offset = target;
+        }
+
+        let body_span = loop_content[0]
+            .span
+            .to(loop_content[loop_content.len() - 1].span);
+
+        let mul_add = match targets[..] {
+            [] => LirOp::WriteZero,
+            [(offset, 1)] => LirOp::MoveCell(offset),
+            _ => LirOp::MulAdd(arena.alloc_slice_copy(&targets)),
+        };
+
+        new_body.push(Node::Op(Spanned::new(LirOp::Move(-offset), body_span)));
+        new_body.push(Node::Op(Spanned::new(mul_add, body_span)));
+
+        Some(Node::Loop(lp.with_body(arena.alloc_slice_copy(&new_body))))
+    }
+
+    /// If a loop body made up of `set` and ending up `offset` cells away is a multiply-add, returns
+    /// the `(offset, factor)`s it adds to
+    ///
+    /// It must end where it started, and decrement its counter by one, so it runs exactly as many
+    /// times as the starting value of the current cell
+    ///
+    /// Unless cells wrap, each cell must only move in one direction, so that it overflows in the
+    /// multiply-add exactly when it would have in the loop
+    fn try_mul_add(
+        set: &[Spanned<LirOp>],
+        offset: isize,
+        config: &Config,
+    ) -> Option<Vec<(isize, isize)>> {
+        if offset != 0 {
+            return None;
+        }
+
+        // Cells can be modified several times in one iteration, so combine them first
+        let mut factors = BTreeMap::new();
+        for op in set {
+            if let LirOp::OffsetModify(delta, offset) = op.op {
+                let factor = factors.entry(offset).or_insert(0isize);
+
+                if config.cell_overflow != Overflow::Wrap && factor.signum() * delta.signum() < 0 {
+                    return None;
+                }
+
+                *factor += delta;
+            }
+        }
+
+        if factors.remove(&0) != Some(-1) {
+            return None;
+        }
+
+        Some(
+            factors
+                .into_iter()
+                .filter(|&(_, factor)| factor != 0)
+                .collect(),
+        )
+    }
+}
+
+pub struct LirInterpreter<'a> {
+    program: Vec<Spanned<LirOp<'a>>>,
+    config: Config,
 }
 
-pub struct LirInterpreter;
+impl<'a> Backend<'a> for LirInterpreter<'a> {
+    fn compile(program: &[Spanned<BfOp>], config: &Config, arena: &'a Bump) -> Result<Self> {
+        let hir = HirGen::gen(program, config);
+
+        Ok(Self {
+            program: LirGen::gen_ir(&hir, config, arena)?,
+            config: *config,
+        })
+    }
+
+    fn run(
+        &self,
+        state: &mut BrainfuckState,
+        input: &mut dyn Read,
+        output: &mut dyn Write,
+    ) -> Result<()> {
+        Ok(Self::execute(
+            &self.program,
+            state,
+            input,
+            output,
+            &self.config,
+        )?)
+    }
+}
 
-impl LirInterpreter {
-    pub fn execute(program: &[LirOp]) -> Result<()> {
+impl LirInterpreter<'_> {
+    pub fn execute(
+        program: &[Spanned<LirOp>],
+        state: &mut BrainfuckState,
+        input: &mut dyn Read,
+        output: &mut dyn Write,
+        config: &Config,
+    ) -> Result<(), RuntimeError> {
         info!("Starting LIR interpreter");
 
+        config.check_state(state)?;
+
         if cfg!(feature = "trace") {
             eprintln!("[Tracing enabled]");
         }
 
-        let branch_table = Self::gen_branch_table(program)?;
+        let branch_table = gen_branch_table(program)?;
 
         let mut instr_pointer = 0;
-        let mut state = BrainfuckState::new();
-
-        let mut stdin = io::stdin().lock();
-        let mut stdout = io::stdout().lock();
+        let mut budget = Budget::new(config);
 
         // Tracing is very simple, only handles non-nested loops
         #[derive(Debug)]
         struct Trace<'a> {
-            loc: (/* start */ usize, /* end */ usize),
+            loc: Span,
             hit_count: usize,
             ops: Vec<LirOp<'a>>,
         }
         let mut traces = HashMap::new();
         let mut last_trace = Vec::new();
-        let mut last_trace_start = usize::MAX;
+        let mut last_trace_start = None;
 
         while instr_pointer < program.len() {
-            let command = unsafe { &program.get_unchecked(instr_pointer) };
+            let Spanned { op: command, span } = unsafe { program.get_unchecked(instr_pointer) };
+            budget.step(*span, state)?;
+
             if cfg!(feature = "trace") {
-                match *command {
-                    command @ LirOp::BrFor => {
+                match (command, last_trace_start) {
+                    (command @ LirOp::BrFor, _) => {
                         last_trace.clear();
                         last_trace.push(*command);
-                        last_trace_start = instr_pointer;
+                        last_trace_start = Some(*span);
                     }
-                    command @ LirOp::BrBack if last_trace_start != usize::MAX => {
+                    (command @ LirOp::BrBack, Some(start)) => {
                         last_trace.push(*command);
-                        let loc = (last_trace_start, instr_pointer);
+                        let loc = start.to(*span);
                         traces
                             .entry(loc)
                             .and_modify(|t: &mut Trace| t.hit_count += 1)
@@ -260,37 +535,29 @@ impl LirInterpreter {
                                 ops: last_trace,
                             });
 
-                        last_trace_start = usize::MAX;
+                        last_trace_start = None;
                         last_trace = Vec::new();
                     }
-                    command if last_trace_start != usize::MAX => last_trace.push(*command),
+                    (command, Some(_)) => last_trace.push(*command),
                     _ => {}
                 }
             }
 
             match command {
                 LirOp::OffsetModify(delta, offset) => {
-                    let target = state.pos.wrapping_add_signed(*offset);
-                    let cur = state.read_cell(target);
-
-                    let new = cur.wrapping_add_signed(*delta as i8);
+                    let target = state.cell_at(*offset, config, *span)?;
 
-                    state.set_cell(new, target);
+                    state
+                        .add_to_cell(*delta as i128, target, config)
+                        .ok_or(RuntimeError::CellOverflow { span: *span })?;
                 }
-                LirOp::Move(delta) => state.pos = state.pos.wrapping_add_signed(*delta),
+                LirOp::Move(delta) => state.pos = state.cell_at(*delta, config, *span)?,
                 LirOp::Out => {
-                    stdout
-                        .write_all(&[state.read_cur_cell()])
-                        .expect("writing to `stdout` failed");
-                }
-                LirOp::In => {
-                    let mut buff = [0; 1];
-                    stdin
-                        .read_exact(&mut buff)
-                        .expect("reading from `stdin` failed");
-
-                    state.set_cur_cell(buff[0]);
+                    output
+                        .write_all(&[state.read_cur_cell() as u8])
+                        .map_err(|err| RuntimeError::io(err, *span))?;
                 }
+                LirOp::In => state.read_input(input, output, config, *span)?,
                 LirOp::BrFor => {
                     if state.read_cur_cell() == 0 {
                         instr_pointer = branch_table[instr_pointer];
@@ -309,18 +576,37 @@ impl LirInterpreter {
                 }
                 LirOp::WriteZero => state.set_cur_cell(0),
                 LirOp::Hop(mov_delta) => {
+                    // Each hop counts as a step, as hopping can go on forever on a tape which wraps or
+                    // saturates
                     while state.read_cur_cell() > 0 {
-                        state.pos = state.pos.wrapping_add_signed(*mov_delta);
+                        budget.step(*span, state)?;
+                        state.pos = state.cell_at(*mov_delta, config, *span)?;
+                    }
+                }
+                LirOp::MulAdd(targets) => {
+                    let cur = state.read_cur_cell();
+
+                    if cur != 0 {
+                        for (offset, factor) in targets.iter() {
+                            let target = state.cell_at(*offset, config, *span)?;
+
+                            state
+                                .add_to_cell(cur as i128 * *factor as i128, target, config)
+                                .ok_or(RuntimeError::CellOverflow { span: *span })?;
+                        }
+
+                        state.set_cur_cell(0);
                     }
                 }
                 LirOp::MoveCell(delta) => {
-                    if state.read_cur_cell() != 0 {
-                        let target = state.pos.wrapping_add_signed(*delta);
+                    let cur = state.read_cur_cell();
 
-                        state.set_cell(
-                            state.read_cell(target).wrapping_add(state.read_cur_cell()),
-                            target,
-                        );
+                    if cur != 0 {
+                        let target = state.cell_at(*delta, config, *span)?;
+
+                        state
+                            .add_to_cell(cur as i128, target, config)
+                            .ok_or(RuntimeError::CellOverflow { span: *span })?;
                         state.set_cur_cell(0);
                     }
                 }
@@ -343,49 +629,115 @@ impl LirInterpreter {
                 }
 
                 eprintln!(
-                    "Trace: hit_count={}, loc=({},{}), ops={}",
+                    "Trace: hit_count={}, loc={}..{} ({}), ops={}",
                     trace.hit_count,
-                    trace.loc.0,
-                    trace.loc.1,
+                    trace.loc.start,
+                    trace.loc.end,
+                    trace.loc,
                     trace.ops.to_compact()
                 );
             }
         }
 
+        output
+            .flush()
+            .map_err(|source| RuntimeError::Io { span: None, source })?;
+
         Ok(())
     }
+}
 
-    fn gen_branch_table(program: &[LirOp]) -> Result<Vec<usize>> {
-        let mut table = vec![0; program.len()];
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::BfParser;
 
-        let mut instr_pointer = 0;
+    fn compact(source: &[u8], config: &Config) -> String {
+        let arena = Bump::new();
+        let program = BfParser::parse(source).unwrap();
+        let hir = HirGen::gen(&program, config);
 
-        while let Some(ref command) = program.get(instr_pointer) {
-            if let LirOp::BrFor = command {
-                let mut depth = 0;
-                let mut pos = instr_pointer;
+        LirGen::gen_ir(&hir, config, &arena).unwrap().to_compact()
+    }
 
-                loop {
-                    pos += 1;
+    #[test]
+    fn folds_loop_around_clear() {
+        let config = Config::default();
+
+        assert_eq!(
+            compact(b"[>[-]<-]", &config),
+            "[Br-> Mov(1) Zero Mov(-1) Zero <-Br] "
+        );
+        assert_eq!(
+            compact(b"[>[-]+>+<<-]", &config),
+            "[Br-> Mov(1) Zero OffsetModify(1, offset: 0) Mov(-1) MovCell(2) <-Br] "
+        );
+        assert_eq!(
+            compact(b"[>>[-]<<->+++<]", &config),
+            "[Br-> Mov(2) Zero Mov(-2) MulAdd(1: *3) <-Br] "
+        );
+    }
 
-                    match program.get(pos) {
-                        Some(LirOp::BrFor) => depth += 1,
-                        Some(LirOp::BrBack) if depth > 0 => depth -= 1,
-                        Some(LirOp::BrBack) => {
-                            table[instr_pointer] = pos;
-                            table[pos] = instr_pointer;
+    #[test]
+    fn keeps_loop_around_clear_which_could_overflow() {
+        let config = Config {
+            cell_overflow: Overflow::Trap,
+            ..Config::default()
+        };
 
-                            break;
-                        }
-                        None => unreachable!("Unterminated bracket, should've been caught earlier"),
-                        _ => {}
-                    }
-                }
-            }
+        // The `+` before the clear overflows on some iteration if the cell ends up at its maximum
+        assert_eq!(
+            compact(b"[>+[-]-<-]", &config),
+            "[Br-> Mov(1) OffsetModify(1, offset: 0) Zero OffsetModify(-1, offset: 0) Mov(-1) OffsetModify(-1, offset: 0) <-Br] "
+        );
+    }
 
-            instr_pointer += 1;
-        }
+    #[test]
+    fn finds_mul_add() {
+        let config = Config::default();
+
+        // Targets are listed by offset, whichever order the loop visits them in
+        assert_eq!(compact(b"[->+++>>++<<<]", &config), "MulAdd(1: *3, 3: *2) ");
+        assert_eq!(
+            compact(b"[-<<++>+++>>>+<<]", &config),
+            "MulAdd(-2: *2, -1: *3, 2: *1) "
+        );
+        assert_eq!(compact(b"[>>+<<->+++<]", &config), "MulAdd(1: *3, 2: *1) ");
+    }
+
+    #[test]
+    fn finds_move_cell() {
+        let config = Config::default();
+
+        assert_eq!(compact(b"[->+<]", &config), "MovCell(1) ");
+        assert_eq!(compact(b"[<+>-]", &config), "MovCell(-1) ");
+        assert_eq!(compact(b"[-<<+>>]", &config), "MovCell(-2) ");
+    }
 
-        Ok(table)
+    #[test]
+    fn keeps_loops_which_arent_mul_add() {
+        let config = Config::default();
+
+        // The counter doesn't go down by one each time
+        assert_eq!(
+            compact(b"[-->+<]", &config),
+            "[Br-> OffsetModify(-2, offset: 0) OffsetModify(1, offset: 1) <-Br] "
+        );
+
+        // I/O in the body
+        assert_eq!(
+            compact(b"[->+.<]", &config),
+            "[Br-> OffsetModify(-1, offset: 0) Mov(1) OffsetModify(1, offset: 0) Out Mov(-1) <-Br] "
+        );
+        assert_eq!(
+            compact(b"[->+<,]", &config),
+            "[Br-> OffsetModify(-1, offset: 0) Mov(1) OffsetModify(1, offset: 0) Mov(-1) In <-Br] "
+        );
+
+        // The pointer ends up somewhere else
+        assert_eq!(
+            compact(b"[->+]", &config),
+            "[Br-> OffsetModify(-1, offset: 0) OffsetModify(1, offset: 1) Mov(1) <-Br] "
+        );
     }
 }
@@ -1,29 +1,22 @@
-#![feature(let_chains)]
-
 use std::{
     env, fs,
-    io::{self, Write},
-    path::PathBuf,
+    io::{self, BufRead, Write},
+    path::{Path, PathBuf},
     time::{Duration, Instant},
 };
 
-use anyhow::{bail, Result};
+use anyhow::Result;
+use bumpalo::Bump;
 use clap::Parser;
-
-use crate::{
-    hir::{HirGen, HirInterpreter},
-    jit::Jit,
-    lir::{LirGen, LirInterpreter},
-    parser::{BfInterpreter, BfParser},
+use rustfuck::{
+    aot::Aot,
+    config::{CellWidth, EofPolicy, Overflow, TapeMode, DEFAULT_MAX_TAPE_LEN, DEFAULT_TAPE_LEN},
+    emit::EmitKind,
+    parser::{BfInterpreter, BfParser, ParseErrorKind},
+    state::BrainfuckState,
+    BackendKind, Config,
 };
 
-mod hir;
-mod ir;
-mod jit;
-mod lir;
-mod parser;
-mod state;
-
 #[derive(Parser)]
 #[command(name = "rustfuck")]
 #[command(author = "John Harry Kelly <johnharrykelly@gmail.com>")]
@@ -33,36 +26,131 @@ mod state;
 )]
 #[command(about, long_about = None)]
 #[command(group(
-    clap::ArgGroup::new("backend")
-        .required(true)
-        .args(&["bf", "hir", "lir", "jit"]),
+    clap::ArgGroup::new("mode")
+        .args(&["backend", "bf", "hir", "lir", "jit", "build", "emit"]),
 ))]
 struct Args {
     /// The file to execute
     /// If not provided, will enter REPL mode
+    #[arg(requires = "mode")]
     file: Option<PathBuf>,
 
+    /// The backend to execute the file with
+    #[arg(short, long, value_enum)]
+    backend: Option<BackendKind>,
+
+    /// Shorthand for `--backend bf`
     #[arg(long)]
     bf: bool,
 
+    /// Shorthand for `--backend hir`
     #[arg(long)]
     hir: bool,
 
+    /// Shorthand for `--backend lir`
     #[arg(long)]
     lir: bool,
 
+    /// Shorthand for `--backend jit`
     #[arg(long)]
     jit: bool,
 
+    /// Instead of running the file, compile it to a standalone x86-64 Linux executable at this
+    /// path
+    #[arg(long, value_name = "OUTPUT")]
+    build: Option<PathBuf>,
+
+    /// Instead of running the file, translate it to another language
+    #[arg(long, value_enum)]
+    emit: Option<EmitKind>,
+
+    /// Where to write the translated file, instead of stdout
+    #[arg(short, long, requires = "emit")]
+    output: Option<PathBuf>,
+
     /// Provide profiling information
     #[arg(short, long)]
     profile: bool,
 
+    /// What `,` does at the end of input
+    #[arg(long, value_enum, default_value_t = EofPolicy::default())]
+    eof: EofPolicy,
+
+    /// How many bits each cell holds
+    #[arg(short, long, value_enum, default_value_t = CellWidth::default())]
+    cell_width: CellWidth,
+
+    /// What happens when a cell goes past 0 or its largest value
+    #[arg(long, value_enum, default_value_t = Overflow::Wrap)]
+    cell_overflow: Overflow,
+
+    /// Whether the tape has ends, or grows on demand in both directions
+    ///
+    /// With `--build` or `--emit`, an infinite tape doesn't grow, but is `--max-tape-len` cells
+    /// with the pointer starting in the middle, so the program can only go half that far either way
+    #[arg(long, value_enum, default_value_t = TapeMode::default())]
+    tape: TapeMode,
+
+    /// What happens when the pointer moves off either end of a fixed tape
+    #[arg(long, value_enum, default_value_t = Overflow::Trap)]
+    pointer_overflow: Overflow,
+
+    /// How many cells are on a fixed tape
+    #[arg(long, default_value_t = DEFAULT_TAPE_LEN)]
+    tape_len: usize,
+
+    /// The most cells any tape can have, a program which needs more fails
+    ///
+    /// With `--build` or `--emit`, an infinite tape has all of these cells from the start, half
+    /// either side of where the pointer starts
+    #[arg(long, default_value_t = DEFAULT_MAX_TAPE_LEN)]
+    max_tape_len: usize,
+
+    /// Stop the program after this many steps, which are ops for the interpreters and loop
+    /// iterations for the JIT
+    #[arg(long)]
+    max_steps: Option<u64>,
+
+    /// Stop the program after this many seconds
+    #[arg(long, value_parser = parse_seconds)]
+    timeout: Option<Duration>,
+
     /// How many times to run the resultant program (for profiling)
     #[arg(short, long, default_value_t = 1)]
     repeat: u32,
 }
 
+impl Args {
+    fn backend(&self) -> BackendKind {
+        match self {
+            Args {
+                backend: Some(backend),
+                ..
+            } => *backend,
+            Args { bf: true, .. } => BackendKind::Bf,
+            Args { hir: true, .. } => BackendKind::Hir,
+            Args { lir: true, .. } => BackendKind::Lir,
+            Args { jit: true, .. } => BackendKind::Jit,
+            // Should be handled by clap
+            _ => unreachable!("pass a backend!"),
+        }
+    }
+
+    fn config(&self) -> Config {
+        Config {
+            eof: self.eof,
+            cell_width: self.cell_width,
+            cell_overflow: self.cell_overflow,
+            tape: self.tape,
+            pointer_overflow: self.pointer_overflow,
+            tape_len: self.tape_len,
+            max_tape_len: self.max_tape_len,
+            step_limit: self.max_steps,
+            time_limit: self.timeout,
+        }
+    }
+}
+
 fn main() -> Result<()> {
     if env::var("RAW_PANIC").is_err() {
         human_panic::setup_panic!();
@@ -72,7 +160,11 @@ fn main() -> Result<()> {
 
     let args = Args::parse();
 
-    let content = fs::read_to_string(args.file.expect("repl disabled"))?;
+    let Some(file) = &args.file else {
+        return repl(&args.config());
+    };
+
+    let content = fs::read_to_string(file)?;
     let content = Vec::from(content.as_bytes());
 
     let (duration, parsed) = run_once(|| BfParser::parse(&content));
@@ -82,65 +174,72 @@ fn main() -> Result<()> {
         println!("Parse took {:?}", duration);
     }
 
-    let duration = if args.bf {
-        run_n(args.repeat, || BfInterpreter::execute(&parsed))
-    } else {
-        let (duration, hir) = run_once(|| HirGen::gen(&parsed));
+    if let Some(output) = &args.build {
+        let (duration, executable) = run_once(|| Aot::compile(&parsed, &args.config()));
 
         if args.profile {
-            println!("HIR gen took {:?}", duration);
+            println!("Compile took {:?}", duration);
         }
 
-        if args.hir {
-            run_n(args.repeat, || HirInterpreter::execute(&hir))
-        } else {
-            let (duration, lir) = run_once(|| LirGen::gen_ir(&hir));
+        return write_executable(output, &executable?);
+    }
+
+    if let Some(kind) = args.emit {
+        let (duration, emitted) = run_once(|| kind.emit(&parsed, &args.config()));
 
-            if args.profile {
-                println!("LIR gen took {:?}", duration);
-            }
+        if args.profile {
+            println!("Compile took {:?}", duration);
+        }
 
-            if args.lir {
-                run_n(args.repeat, || LirInterpreter::execute(&lir))
-            } else if args.jit {
-                if cfg!(not(target_arch = "aarch64")) {
-                    bail!("The `--jit` feature is currently only supported on ARM64");
-                }
+        return match &args.output {
+            Some(output) => Ok(fs::write(output, emitted?)?),
+            None => Ok(io::stdout().write_all(&emitted?)?),
+        };
+    }
 
-                let (duration, func) = run_once(|| Jit::jit(&lir));
-                let (func_buff, func) = func?;
+    let arena = Bump::new();
+    let (duration, program) = run_once(|| args.backend().compile(&parsed, &args.config(), &arena));
+    let program = program?;
 
-                if args.profile {
-                    println!("JIT took {:?}", duration);
-                }
+    if args.profile {
+        println!("Compile took {:?}", duration);
+    }
 
-                let mut cells = [0u8; 30_000];
-                let mut buff = [0u8; 30_000];
+    let duration = run_n(args.repeat, || {
+        program.run(
+            &mut BrainfuckState::new(),
+            &mut io::stdin().lock(),
+            &mut io::stdout().lock(),
+        )
+    })?;
 
-                let result = run_n(args.repeat, || {
-                    func(cells.as_mut_ptr(), buff.as_mut_ptr());
-                    Ok(())
-                });
+    if args.profile {
+        println!("Execution took: {:?}", duration);
+    }
 
-                io::stdout().write_all(&buff[0..buff.iter().position(|&b| b == 0).unwrap()])?;
+    Ok(())
+}
 
-                let _ = func_buff; // Backing memory is now safe to drop
+/// Writes out an executable, marking it as such on platforms which care
+fn write_executable(path: &Path, contents: &[u8]) -> Result<()> {
+    fs::write(path, contents)?;
 
-                result
-            } else {
-                // Should be handled by clap
-                unreachable!("pass a backend!");
-            }
-        }
-    };
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
 
-    if let Ok(duration) = duration && args.profile {
-        println!("Execution took: {:?}", duration);
+        fs::set_permissions(path, fs::Permissions::from_mode(0o755))?;
     }
 
     Ok(())
 }
 
+fn parse_seconds(arg: &str) -> Result<Duration, String> {
+    let seconds = arg.parse::<f64>().map_err(|err| err.to_string())?;
+
+    Duration::try_from_secs_f64(seconds).map_err(|err| err.to_string())
+}
+
 fn run_n<T>(count: u32, mut f: impl FnMut() -> Result<T>) -> Result<Duration> {
     let start = Instant::now();
 
@@ -163,67 +262,217 @@ fn run_once<R>(f: impl FnOnce() -> R) -> (Duration, R) {
     (end - start, result)
 }
 
-// // # of cells shown either side of current one
-// const CELLS_SHOWN: usize = 5;
+/// # of cells shown either side of the current one
+const CELLS_SHOWN: usize = 5;
 
-// fn repl(mut interpreter: Interpreter) -> Result<()> {
-//     let mut line = String::new();
+fn repl(config: &Config) -> Result<()> {
+    run_repl(
+        &mut io::stdin().lock(),
+        &mut io::stdout().lock(),
+        &mut io::stderr(),
+        config,
+    )
+}
 
-//     loop {
-//         print!("> ");
-//         io::stdout().flush()?;
+/// Reads lines from `input` and runs each one as it is finished, on a tape which carries over
+/// between them. Programs read `,` from `input` too, and everything is written to `output` apart
+/// from errors, which go to `errors` and don't end the session
+fn run_repl(
+    input: &mut impl BufRead,
+    output: &mut impl Write,
+    errors: &mut impl Write,
+    config: &Config,
+) -> Result<()> {
+    writeln!(
+        output,
+        "rustfuck REPL - `reset` clears the tape, `dump` shows all of it, `quit` exits"
+    )?;
+
+    let mut state = BrainfuckState::new();
+    let mut line = String::new();
+
+    loop {
+        write!(output, "{}", if line.is_empty() { "> " } else { ". " })?;
+        output.flush()?;
+
+        let mut additional = String::new();
+        if input.read_line(&mut additional)? == 0 {
+            // End of input
+            writeln!(output)?;
+            break;
+        }
+
+        // Commands are only recognised on their own line, not in the middle of a loop
+        if line.is_empty() {
+            match additional.trim() {
+                "q" | "quit" => {
+                    writeln!(output, "Terminating...")?;
+                    break;
+                }
+                "reset" => {
+                    state = BrainfuckState::new();
+                    print_tape_window(output, &state, config)?;
+                    continue;
+                }
+                "dump" => {
+                    print_tape(output, &state, config)?;
+                    continue;
+                }
+                _ => {}
+            }
+        }
 
-//         let mut additional = String::new();
-//         io::stdin().read_line(&mut additional)?;
+        line.push_str(&additional);
+
+        // Don't execute a malformed line, but let loops continue over multiple lines
+        let program = match BfParser::parse(line.as_bytes()) {
+            Ok(program) => program,
+            Err(err) if err.kind == ParseErrorKind::UnmatchedOpen => continue,
+            Err(err) => {
+                writeln!(errors, "Error: {err}")?;
+                writeln!(errors, "Line was discarded")?;
+                line.clear();
+                continue;
+            }
+        };
 
-//         if additional.trim() == "q" {
-//             println!("Terminating...");
-//             break;
-//         }
+        line.clear();
 
-//         line.push_str(&additional);
+        let result = BfInterpreter::execute(&program, &mut state, input, output, config);
+        output.flush()?;
 
-//         // Need to do a check for unmatched braces so we don't execute a malformed line
-//         let line_bytes = line.as_bytes();
-//         if line_bytes.iter().filter(|&&b| b == b'[').count()
-//             != line_bytes.iter().filter(|&&b| b == b']').count()
-//         {
-//             println!("(unterminated `[` or `]` - enter next line)");
-//             continue;
-//         }
+        if let Err(err) = result {
+            writeln!(errors, "Error: {err}")?;
+        }
 
-//         if let Err(err) = interpreter.execute(line_bytes) {
-//             eprintln!("Error: {}", err);
-//             eprintln!("Line was discarded");
-//         }
+        print_tape_window(output, &state, config)?;
+    }
 
-//         line.clear();
+    Ok(())
+}
 
-//         let state = interpreter.state();
+/// How many digits the largest value of a cell takes up
+fn cell_digits(config: &Config) -> usize {
+    config.cell_width.max().to_string().len()
+}
 
-//         let mut cell_row = String::new();
+/// Prints the cells around the current one, with a marker under the current one
+fn print_tape_window(
+    output: &mut impl Write,
+    state: &BrainfuckState,
+    config: &Config,
+) -> io::Result<()> {
+    let digits = cell_digits(config);
+    let mut cell_row = String::new();
+
+    if state.pos > CELLS_SHOWN {
+        cell_row.push_str("...");
+    }
 
-//         if state.pos > CELLS_SHOWN {
-//             cell_row.push_str("...");
-//         }
+    let start_pos = state.pos.saturating_sub(CELLS_SHOWN);
 
-//         let start_pos = state.pos.saturating_sub(CELLS_SHOWN);
+    for cell_index in start_pos..state.pos + CELLS_SHOWN + 1 {
+        cell_row.push_str(&format!("|{:0digits$}", state.read_cell(cell_index)));
+    }
 
-//         for cell_index in start_pos..state.pos + CELLS_SHOWN + 1 {
-//             cell_row.push_str(&format!("|{:03}", state.read_cell(cell_index)));
-//         }
+    writeln!(output, "\n{}|...", cell_row)?;
 
-//         println!("\n{}|...", cell_row);
+    // Each cell is `digits` chars with a 1-byte prefix
+    let mut cur_cell_pos = (state.pos - start_pos) * (digits + 1);
 
-//         // Each cell is 3 char with a 1-byte prefix
-//         let mut cur_cell_pos = (state.pos - start_pos) * 4;
+    if state.pos > CELLS_SHOWN {
+        cur_cell_pos += 3;
+    }
 
-//         if state.pos > CELLS_SHOWN {
-//             cur_cell_pos += 3;
-//         }
+    writeln!(output, "{:1$}{2}", "", cur_cell_pos + 1, "^".repeat(digits))
+}
 
-//         println!("{:1$}^^^", "", cur_cell_pos + 1);
-//     }
+/// Prints every cell which has been touched, and where the pointer is
+fn print_tape(output: &mut impl Write, state: &BrainfuckState, config: &Config) -> io::Result<()> {
+    let digits = cell_digits(config);
+    let len = state.cells.len().max(state.pos + 1);
+
+    // Cells are numbered from where the tape started, so any to its left are negative
+    for start in (0..len).step_by(16) {
+        let mut cell_row = format!("{:>6}:", start as isize - state.origin as isize);
+
+        for cell_index in start..len.min(start + 16) {
+            let marker = if cell_index == state.pos { '*' } else { ' ' };
+            cell_row.push_str(&format!(
+                " {marker}{:0digits$}",
+                state.read_cell(cell_index)
+            ));
+        }
 
-//     Ok(())
-// }
+        writeln!(output, "{cell_row}")?;
+    }
+
+    writeln!(output, "pointer at cell {}", state.pointer())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Runs a REPL session on `input`, returning what it output and the errors it reported
+    fn session(input: &str) -> (String, String) {
+        let mut output = Vec::new();
+        let mut errors = Vec::new();
+
+        run_repl(
+            &mut input.as_bytes(),
+            &mut output,
+            &mut errors,
+            &Config::default(),
+        )
+        .unwrap();
+
+        (
+            String::from_utf8(output).unwrap(),
+            String::from_utf8(errors).unwrap(),
+        )
+    }
+
+    #[test]
+    fn tape_carries_over_between_lines() {
+        let a = "+".repeat(65);
+        let (output, errors) = session(&format!("{a}\n>++\n<.\n"));
+
+        assert_eq!(errors, "");
+        assert!(output.contains("|065|002|000"), "{output}");
+        assert!(output.contains("> A\n"), "{output}");
+    }
+
+    #[test]
+    fn loops_can_span_lines() {
+        let (output, errors) = session("+++[\n->+\n<]>.\n");
+
+        assert_eq!(errors, "");
+        assert!(output.contains("> . . \u{3}\n"), "{output}");
+    }
+
+    #[test]
+    fn errors_dont_end_session() {
+        let a = "+".repeat(65);
+        let (output, errors) = session(&format!("+\n<\n]\n{a}.\n"));
+
+        assert_eq!(
+            errors,
+            "Error: Tried to move the pointer to cell -1, outside of the tape (0..30000), at 1:1\n\
+             Error: Unmatched `]` at line 1, column 1\n  |\n1 | ]\n  | ^\n\
+             Line was discarded\n"
+        );
+
+        // The tape is as the failing line left it
+        assert!(output.contains("> B\n"), "{output}");
+    }
+
+    #[test]
+    fn commands() {
+        let (output, _) = session("+++\nreset\ndump\nquit\n+.\n");
+
+        // The tape is cleared before it is dumped, and nothing after `quit` runs
+        assert!(output.contains("0: *000\npointer at cell 0\n"), "{output}");
+        assert!(output.ends_with("> Terminating...\n"), "{output}");
+    }
+}
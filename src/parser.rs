@@ -1,72 +1,236 @@
-use std::io::{self, Read, Write};
+use std::{
+    error::Error,
+    fmt::{self, Display},
+    io::{Read, Write},
+};
 
 use anyhow::Result;
+use bumpalo::Bump;
 
+use crate::backend::Backend;
+use crate::config::Config;
+use crate::error::RuntimeError;
 use crate::hir::BfOp;
+use crate::ir::{Span, Spanned};
+use crate::limit::Budget;
 use crate::state::BrainfuckState;
 
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum ParseErrorKind {
+    /// A `[` with no `]` after it
+    UnmatchedOpen,
+    /// A `]` with no `[` before it
+    UnmatchedClose,
+}
+
+/// A malformed program, pointing at the offending bracket
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct ParseError {
+    pub kind: ParseErrorKind,
+    /// 1-based
+    pub line: usize,
+    /// 1-based, in characters rather than bytes
+    pub column: usize,
+    /// The full line the bracket is on
+    pub snippet: String,
+}
+
+impl ParseError {
+    fn new(kind: ParseErrorKind, program: &[u8], pos: usize) -> Self {
+        let line_start = program[..pos]
+            .iter()
+            .rposition(|&b| b == b'\n')
+            .map_or(0, |i| i + 1);
+        let line_end = program[pos..]
+            .iter()
+            .position(|&b| b == b'\n')
+            .map_or(program.len(), |i| pos + i);
+
+        let line = program[..line_start]
+            .iter()
+            .filter(|&&b| b == b'\n')
+            .count()
+            + 1;
+        let column = String::from_utf8_lossy(&program[line_start..pos])
+            .chars()
+            .count()
+            + 1;
+        let snippet = String::from_utf8_lossy(&program[line_start..line_end])
+            .trim_end()
+            .to_owned();
+
+        Self {
+            kind,
+            line,
+            column,
+            snippet,
+        }
+    }
+}
+
+impl Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let bracket = match self.kind {
+            ParseErrorKind::UnmatchedOpen => '[',
+            ParseErrorKind::UnmatchedClose => ']',
+        };
+
+        writeln!(
+            f,
+            "Unmatched `{bracket}` at line {}, column {}",
+            self.line, self.column
+        )?;
+
+        // Tabs are kept under the caret so it lines up however wide they are shown
+        let indent = self
+            .snippet
+            .chars()
+            .take(self.column - 1)
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect::<String>();
+
+        let gutter = self.line.to_string().len();
+        writeln!(f, "{:gutter$} |", "")?;
+        writeln!(f, "{} | {}", self.line, self.snippet)?;
+        write!(f, "{:gutter$} | {indent}^", "")
+    }
+}
+
+impl Error for ParseError {}
+
 pub struct BfParser;
 
 impl BfParser {
-    pub fn parse(program: &[u8]) -> Result<Vec<BfOp>> {
+    pub fn parse(program: &[u8]) -> Result<Vec<Spanned<BfOp>>, ParseError> {
         let mut ir = Vec::new();
 
-        for command in program.iter() {
+        // Positions of the currently open `[`s, so we can reject unbalanced programs up front
+        let mut open_loops = Vec::new();
+
+        let mut line = 1;
+        let mut column = 0;
+
+        for (pos, command) in program.iter().enumerate() {
             match command {
-                b'+' => ir.push(BfOp::Inc),
-                b'-' => ir.push(BfOp::Dec),
-                b'>' => ir.push(BfOp::MvRight),
-                b'<' => ir.push(BfOp::MvLeft),
-                b'.' => ir.push(BfOp::Out),
-                b',' => ir.push(BfOp::In),
-                b'[' => ir.push(BfOp::BrFor),
-                b']' => ir.push(BfOp::BrBack),
+                b'\n' => {
+                    line += 1;
+                    column = 0;
+                }
+                // Continuation bytes are part of the same character as the one before
+                b if b & 0xC0 != 0x80 => column += 1,
+                _ => {}
+            }
 
-                _ => continue,
+            match command {
+                b'[' => open_loops.push(pos),
+                b']' if open_loops.pop().is_none() => {
+                    return Err(ParseError::new(
+                        ParseErrorKind::UnmatchedClose,
+                        program,
+                        pos,
+                    ));
+                }
+                _ => {}
             }
+
+            let op = match command {
+                b'+' => BfOp::Inc,
+                b'-' => BfOp::Dec,
+                b'>' => BfOp::MvRight,
+                b'<' => BfOp::MvLeft,
+                b'.' => BfOp::Out,
+                b',' => BfOp::In,
+                b'[' => BfOp::BrFor,
+                b']' => BfOp::BrBack,
+
+                _ => continue,
+            };
+
+            let span = Span {
+                start: pos,
+                end: pos + 1,
+                line,
+                column,
+            };
+
+            ir.push(Spanned::new(op, span));
+        }
+
+        if let Some(&pos) = open_loops.last() {
+            return Err(ParseError::new(ParseErrorKind::UnmatchedOpen, program, pos));
         }
 
         Ok(ir)
     }
 }
 
-pub struct BfInterpreter;
+pub struct BfInterpreter {
+    program: Vec<Spanned<BfOp>>,
+    config: Config,
+}
+
+impl Backend<'_> for BfInterpreter {
+    fn compile(program: &[Spanned<BfOp>], config: &Config, _arena: &Bump) -> Result<Self> {
+        Ok(Self {
+            program: program.to_vec(),
+            config: *config,
+        })
+    }
+
+    fn run(
+        &self,
+        state: &mut BrainfuckState,
+        input: &mut dyn Read,
+        output: &mut dyn Write,
+    ) -> Result<()> {
+        Ok(Self::execute(
+            &self.program,
+            state,
+            input,
+            output,
+            &self.config,
+        )?)
+    }
+}
 
 impl BfInterpreter {
-    pub fn execute(program: &[BfOp]) -> Result<()> {
+    /// Runs `program` starting from `state`, leaving it as the program left it
+    ///
+    /// If the program fails part way through, `state` is left as it was at the point of failure
+    pub fn execute(
+        program: &[Spanned<BfOp>],
+        state: &mut BrainfuckState,
+        input: &mut dyn Read,
+        output: &mut dyn Write,
+        config: &Config,
+    ) -> Result<(), RuntimeError> {
+        config.check_state(state)?;
+
         let mut instr_pointer = 0;
+        let mut budget = Budget::new(config);
 
-        let mut state = BrainfuckState {
-            cells: Vec::new(),
-            pos: 0,
-        };
+        while let Some(command) = program.get(instr_pointer) {
+            budget.step(command.span, state)?;
 
-        let mut stdout = io::stdout().lock();
-        let mut stdin = io::stdin().lock();
+            match command.op {
+                BfOp::MvRight | BfOp::MvLeft => {
+                    let delta = if command.op == BfOp::MvRight { 1 } else { -1 };
 
-        while let Some(command) = program.get(instr_pointer) {
-            match *command {
-                BfOp::MvRight => state.pos += 1,
-                BfOp::MvLeft if state.pos == 0 => panic!(
-                    "Tried to decrement data pointer below 0 at position {}",
-                    instr_pointer
-                ),
-                BfOp::MvLeft => state.pos -= 1,
-                BfOp::Inc => state.modify_cur_cell_by(1),
-                BfOp::Dec => state.modify_cur_cell_by(-1),
-                BfOp::Out => {
-                    stdout
-                        .write_all(&[state.read_cur_cell()])
-                        .expect("writing to `stdout` failed");
+                    state.pos = state.cell_at(delta, config, command.span)?;
                 }
-                BfOp::In => {
-                    let mut buff = [0; 1];
-                    stdin
-                        .read_exact(&mut buff)
-                        .expect("reading from `stdin` failed");
+                BfOp::Inc | BfOp::Dec => {
+                    let delta = if command.op == BfOp::Inc { 1 } else { -1 };
 
-                    state.set_cur_cell(buff[0]);
+                    state
+                        .add_to_cur_cell(delta, config)
+                        .ok_or(RuntimeError::CellOverflow { span: command.span })?;
+                }
+                BfOp::Out => {
+                    output
+                        .write_all(&[state.read_cur_cell() as u8])
+                        .map_err(|err| RuntimeError::io(err, command.span))?;
                 }
+                BfOp::In => state.read_input(input, output, config, command.span)?,
 
                 BfOp::BrFor if state.read_cur_cell() == 0 => {
                     let mut depth = 0;
@@ -75,7 +239,7 @@ impl BfInterpreter {
                     loop {
                         pos += 1;
 
-                        match program.get(pos) {
+                        match program.get(pos).map(|op| &op.op) {
                             Some(BfOp::BrFor) => depth += 1,
                             Some(BfOp::BrBack) if depth > 0 => depth -= 1,
                             Some(BfOp::BrBack) => {
@@ -85,7 +249,9 @@ impl BfInterpreter {
                                 instr_pointer = pos;
                                 break;
                             }
-                            None => panic!("Unmatched '[' at position {}", instr_pointer),
+                            None => {
+                                return Err(RuntimeError::UnmatchedBracket { span: command.span })
+                            }
                             _ => {}
                         }
                     }
@@ -96,19 +262,21 @@ impl BfInterpreter {
                     let mut pos = instr_pointer;
 
                     loop {
-                        pos -= 1;
+                        // Running off the start of the program means there's no matching bracket
+                        pos = pos
+                            .checked_sub(1)
+                            .ok_or(RuntimeError::UnmatchedBracket { span: command.span })?;
 
-                        match program.get(pos) {
-                            Some(BfOp::BrBack) => depth += 1,
-                            Some(BfOp::BrFor) if depth > 0 => depth -= 1,
-                            Some(BfOp::BrFor) => {
+                        match program[pos].op {
+                            BfOp::BrBack => depth += 1,
+                            BfOp::BrFor if depth > 0 => depth -= 1,
+                            BfOp::BrFor => {
                                 // Reached the matching bracket
                                 // The next instruction we want to execute is the one AFTER this,
                                 // but we increment instr_pointer at the end of the loop
                                 instr_pointer = pos;
                                 break;
                             }
-                            None => panic!("Unmatched ']' at position {}", instr_pointer),
                             _ => {}
                         }
                     }
@@ -120,6 +288,80 @@ impl BfInterpreter {
             instr_pointer += 1;
         }
 
+        output
+            .flush()
+            .map_err(|source| RuntimeError::Io { span: None, source })?;
+
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_error(program: &[u8]) -> ParseError {
+        BfParser::parse(program).unwrap_err()
+    }
+
+    #[test]
+    fn unmatched_open() {
+        let err = parse_error(b"+[->+<]\n[>+");
+
+        assert_eq!(err.kind, ParseErrorKind::UnmatchedOpen);
+        assert_eq!((err.line, err.column), (2, 1));
+        assert_eq!(err.snippet, "[>+");
+        assert_eq!(
+            err.to_string(),
+            "Unmatched `[` at line 2, column 1\n  |\n2 | [>+\n  | ^"
+        );
+
+        // The innermost open loop is the one reported
+        let err = parse_error(b"+[[-]");
+        assert_eq!((err.line, err.column), (1, 2));
+        assert_eq!(
+            err.to_string(),
+            "Unmatched `[` at line 1, column 2\n  |\n1 | +[[-]\n  |  ^"
+        );
+    }
+
+    #[test]
+    fn unmatched_close() {
+        let err = parse_error(b"+]");
+
+        assert_eq!(err.kind, ParseErrorKind::UnmatchedClose);
+        assert_eq!((err.line, err.column), (1, 2));
+        assert_eq!(
+            err.to_string(),
+            "Unmatched `]` at line 1, column 2\n  |\n1 | +]\n  |  ^"
+        );
+
+        // Lines with more digits widen the gutter
+        let err = parse_error(b"+\n\n\n\n\n\n\n\n\n[-]  ] comment  \n+");
+        assert_eq!((err.line, err.column), (10, 6));
+        assert_eq!(err.snippet, "[-]  ] comment");
+        assert_eq!(
+            err.to_string(),
+            "Unmatched `]` at line 10, column 6\n   |\n10 | [-]  ] comment\n   |      ^"
+        );
+    }
+
+    #[test]
+    fn columns_count_characters() {
+        // `é` is two bytes, but one character
+        let err = parse_error("café ]".as_bytes());
+        assert_eq!((err.line, err.column), (1, 6));
+        assert_eq!(
+            err.to_string(),
+            "Unmatched `]` at line 1, column 6\n  |\n1 | café ]\n  |      ^"
+        );
+
+        // A tab is one character, and is kept under the caret
+        let err = parse_error(b"+\n\t+\t[");
+        assert_eq!((err.line, err.column), (2, 4));
+        assert_eq!(
+            err.to_string(),
+            "Unmatched `[` at line 2, column 4\n  |\n2 | \t+\t[\n  | \t \t^"
+        );
+    }
+}
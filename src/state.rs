@@ -1,7 +1,19 @@
-#[derive(Debug)]
+use std::{
+    io::{Read, Write},
+    iter,
+};
+
+use crate::config::{Config, TapeMode};
+use crate::error::RuntimeError;
+use crate::ir::Span;
+
+#[derive(Debug, Default)]
 pub struct BrainfuckState {
-    pub cells: Vec<u8>,
+    /// Every cell is stored as a `u32`, whatever the width the program runs with
+    pub cells: Vec<u32>,
     pub pos: usize,
+    /// Where the first cell of the tape has ended up, after an infinite tape has grown to the left
+    pub origin: usize,
 }
 
 impl BrainfuckState {
@@ -9,19 +21,95 @@ impl BrainfuckState {
         Self {
             cells: Vec::new(),
             pos: 0,
+            origin: 0,
+        }
+    }
+
+    /// Where the pointer is relative to the first cell of the tape, which can be negative once an
+    /// infinite tape has grown to the left
+    #[inline]
+    pub fn pointer(&self) -> isize {
+        self.pos as isize - self.origin as isize
+    }
+
+    /// Adds at least `by` cells to the left of the tape, keeping the pointer and origin on the same
+    /// cells as before. Returns how many were added
+    ///
+    /// The tape grows by up to its own length each time (without going over `max_len` cells), so
+    /// moving left one cell at a time doesn't have to shift everything each step
+    pub fn grow_left(&mut self, by: usize, max_len: usize) -> usize {
+        let len = self.cells.len();
+        let by = len.min(max_len.saturating_sub(len)).max(by);
+
+        self.cells.splice(0..0, iter::repeat_n(0, by));
+        self.pos += by;
+        self.origin += by;
+
+        by
+    }
+
+    /// The index of the cell `offset` away from the current one, which is where the pointer ends
+    /// up if it is moved by `offset`
+    ///
+    /// An infinite tape is grown to the left if the cell is before its start, while a fixed tape
+    /// wraps or clamps as `config` says, failing if overflow traps. `span` is the instruction
+    /// doing the moving, for the error
+    #[inline]
+    pub fn cell_at(
+        &mut self,
+        offset: isize,
+        config: &Config,
+        span: Span,
+    ) -> Result<usize, RuntimeError> {
+        let pos = (self.pos as isize).saturating_add(offset);
+
+        match config.tape {
+            TapeMode::Fixed => config
+                .pointer_at(pos)
+                .ok_or(RuntimeError::PointerOutOfRange {
+                    span,
+                    cell: pos,
+                    len: config.tape_len,
+                }),
+            TapeMode::Infinite => {
+                // Cells to the right of those written to haven't been added yet, but still count
+                let len = self.cells.len().max(self.pos + 1);
+                let needed = if pos < 0 {
+                    len.saturating_add(pos.unsigned_abs())
+                } else {
+                    len.max(pos as usize + 1)
+                };
+
+                if needed > config.max_tape_len {
+                    return Err(RuntimeError::TapeTooLarge {
+                        span: Some(span),
+                        len: needed,
+                        max: config.max_tape_len,
+                    });
+                }
+
+                if pos < 0 {
+                    self.grow_left(pos.unsigned_abs(), config.max_tape_len);
+                }
+
+                // Cells to the right are added as they are written to
+                Ok((self.pos as isize + offset) as usize)
+            }
         }
     }
 
-    pub fn read_cell(&self, i: usize) -> u8 {
+    #[inline]
+    pub fn read_cell(&self, i: usize) -> u32 {
         // If the cell is OOB, it cannot have been written to, so must be zero
-        *self.cells.get(i).unwrap_or(&0u8)
+        *self.cells.get(i).unwrap_or(&0)
     }
 
-    pub fn read_cur_cell(&self) -> u8 {
+    pub fn read_cur_cell(&self) -> u32 {
         self.read_cell(self.pos)
     }
 
-    pub fn set_cell(&mut self, val: u8, i: usize) {
+    #[inline]
+    pub fn set_cell(&mut self, val: u32, i: usize) {
         if i >= self.cells.len() {
             self.cells.resize(i + 1, 0);
         }
@@ -29,11 +117,11 @@ impl BrainfuckState {
         self.cells[i] = val;
     }
 
-    pub fn set_cur_cell(&mut self, val: u8) {
+    pub fn set_cur_cell(&mut self, val: u32) {
         self.set_cell(val, self.pos);
     }
 
-    pub fn modify_cur_cell_with(&mut self, f: impl Fn(&mut u8)) {
+    pub fn modify_cur_cell_with(&mut self, f: impl Fn(&mut u32)) {
         if self.pos >= self.cells.len() {
             self.cells.resize(self.pos + 1, 0);
         }
@@ -42,11 +130,43 @@ impl BrainfuckState {
         unsafe { f(self.cells.get_unchecked_mut(self.pos)) };
     }
 
-    pub fn modify_cur_cell_by(&mut self, arg: i32) {
-        if self.pos >= self.cells.len() {
-            self.cells.resize(self.pos + 1, 0);
+    /// Reads a byte from `input` into the current cell for the `,` at `span`, or handles the end of
+    /// input as `config` says
+    ///
+    /// Interactive programs usually prompt before reading, so `output` is flushed first to make
+    /// sure the prompt is visible
+    pub fn read_input(
+        &mut self,
+        input: &mut dyn Read,
+        output: &mut dyn Write,
+        config: &Config,
+        span: Span,
+    ) -> Result<(), RuntimeError> {
+        output.flush().map_err(|err| RuntimeError::io(err, span))?;
+
+        let value = config
+            .eof
+            .read(input)
+            .map_err(|err| RuntimeError::io(err, span))?;
+
+        if let Some(value) = value {
+            self.set_cur_cell(config.cell_width.wrap(value));
         }
 
-        self.cells[self.pos] = (self.cells[self.pos] as i32 + arg) as u8;
+        Ok(())
+    }
+
+    /// Adds `delta` to the cell at `i` as `config` says, returning the new value, or `None` if it
+    /// overflowed and overflow traps
+    #[inline]
+    pub fn add_to_cell(&mut self, delta: i128, i: usize, config: &Config) -> Option<u32> {
+        let val = config.add_to_cell(self.read_cell(i), delta)?;
+        self.set_cell(val, i);
+
+        Some(val)
+    }
+
+    pub fn add_to_cur_cell(&mut self, delta: i128, config: &Config) -> Option<u32> {
+        self.add_to_cell(delta, self.pos, config)
     }
 }
@@ -0,0 +1,113 @@
+use std::mem;
+
+use bumpalo::Bump;
+
+use crate::error::RuntimeError;
+use crate::ir::{LoopMarkers, Span, Spanned};
+
+/// A program where each loop owns its body, rather than being delimited by a pair of bracket ops
+///
+/// Bodies live in an arena, so nodes are cheap to copy and rebuild while optimising
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum Node<'a, T> {
+    Op(Spanned<T>),
+    Loop(Loop<'a, T>),
+}
+
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub struct Loop<'a, T> {
+    /// Spans of the `[` and `]`
+    pub open: Span,
+    pub close: Span,
+    pub body: &'a [Node<'a, T>],
+}
+
+impl<'a, T: Copy> Loop<'a, T> {
+    /// The whole loop, brackets included
+    pub fn span(&self) -> Span {
+        self.open.to(self.close)
+    }
+
+    /// The ops in the body, if it has no loops of its own
+    pub fn simple_body(&self) -> Option<Vec<Spanned<T>>> {
+        self.body
+            .iter()
+            .map(|node| match node {
+                Node::Op(op) => Some(*op),
+                Node::Loop(_) => None,
+            })
+            .collect()
+    }
+
+    /// The same loop around a different body
+    pub fn with_body(&self, body: &'a [Node<'a, T>]) -> Self {
+        Self { body, ..*self }
+    }
+}
+
+/// Builds the tree for a flat `program`
+///
+/// Programs from the parser always have matching brackets, but hand-built ones might not
+pub fn build<'a, T: LoopMarkers>(
+    program: &[Spanned<T>],
+    arena: &'a Bump,
+) -> Result<&'a [Node<'a, T>], RuntimeError> {
+    // Programs can nest loops deeper than the call stack would allow, so keep our own. Each frame
+    // is an open loop's `[`, along with the nodes around it built so far
+    let mut stack = Vec::new();
+    let mut nodes = Vec::new();
+
+    for op in program {
+        if op.op == T::BR_FOR {
+            stack.push((op.span, mem::take(&mut nodes)));
+        } else if op.op == T::BR_BACK {
+            let (open, outer) = stack
+                .pop()
+                .ok_or(RuntimeError::UnmatchedBracket { span: op.span })?;
+            let body = arena.alloc_slice_copy(&mem::replace(&mut nodes, outer));
+
+            nodes.push(Node::Loop(Loop {
+                open,
+                close: op.span,
+                body,
+            }));
+        } else {
+            nodes.push(Node::Op(*op));
+        }
+    }
+
+    // Only the innermost unclosed loop is reported, as `gen_branch_table` does
+    match stack.pop() {
+        Some((open, _)) => Err(RuntimeError::UnmatchedBracket { span: open }),
+        None => Ok(arena.alloc_slice_copy(&nodes)),
+    }
+}
+
+/// Flattens `nodes` back into ops, with loops delimited by bracket ops
+pub fn lower<T: LoopMarkers>(nodes: &[Node<T>]) -> Vec<Spanned<T>> {
+    let mut program = Vec::new();
+
+    // Each frame is what is left of the body around a loop being lowered, along with the loop's `]`
+    let mut stack = Vec::new();
+    let mut body = nodes.iter();
+
+    loop {
+        match body.next() {
+            Some(Node::Op(op)) => program.push(*op),
+            Some(Node::Loop(lp)) => {
+                program.push(Spanned::new(T::BR_FOR, lp.open));
+                stack.push((mem::replace(&mut body, lp.body.iter()), lp.close));
+            }
+            None => {
+                let Some((outer, close)) = stack.pop() else {
+                    break;
+                };
+
+                program.push(Spanned::new(T::BR_BACK, close));
+                body = outer;
+            }
+        }
+    }
+
+    program
+}
@@ -0,0 +1,168 @@
+//! Builds executables and runs them, checking they behave like the LIR interpreter. They are
+//! x86-64 Linux ELF files, so can only be run there
+
+#![cfg(all(target_os = "linux", target_arch = "x86_64"))]
+
+use std::fs;
+use std::io::Write;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use bumpalo::Bump;
+use rustfuck::aot::Aot;
+use rustfuck::config::{EofPolicy, Overflow};
+use rustfuck::parser::BfParser;
+use rustfuck::state::BrainfuckState;
+use rustfuck::{BackendKind, Config, Program};
+
+/// What running an executable left behind
+#[derive(Debug)]
+struct Run {
+    output: Vec<u8>,
+    code: Option<i32>,
+    stderr: String,
+}
+
+/// Builds `source` into an executable called `name`, returning where it is
+fn build(name: &str, source: &[u8], config: &Config) -> PathBuf {
+    let program = BfParser::parse(source).unwrap();
+
+    let dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join("aot");
+    fs::create_dir_all(&dir).unwrap();
+
+    let path = dir.join(name);
+    fs::write(&path, Aot::compile(&program, config).unwrap()).unwrap();
+    fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+
+    path
+}
+
+/// Builds `source` into an executable called `name` and runs it on `input`
+fn run_executable(name: &str, source: &[u8], config: &Config, input: &[u8]) -> Run {
+    let path = build(name, source, config);
+
+    let mut child = Command::new(&path)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+
+    child.stdin.take().unwrap().write_all(input).unwrap();
+
+    let output = child.wait_with_output().unwrap();
+
+    Run {
+        output: output.stdout,
+        code: output.status.code(),
+        stderr: String::from_utf8(output.stderr).unwrap(),
+    }
+}
+
+/// Runs `source` on the LIR interpreter, for the executable to be compared against
+fn run_lir(source: &[u8], config: &Config, input: &[u8]) -> (Vec<u8>, Option<String>) {
+    let arena = Bump::new();
+    let program = Program::compile(source, BackendKind::Lir, config, &arena).unwrap();
+
+    let mut output = Vec::new();
+    let result = program.run(&mut BrainfuckState::new(), &mut &input[..], &mut output);
+
+    (output, result.err().map(|err| err.to_string()))
+}
+
+#[test]
+fn hello_world() {
+    let run = run_executable(
+        "hello_world",
+        include_bytes!("../examples/hello_world.b"),
+        &Config::default(),
+        b"",
+    );
+
+    assert_eq!(run.output, b"Hello World!\n");
+    assert_eq!(run.code, Some(0));
+    assert_eq!(run.stderr, "");
+}
+
+#[test]
+fn echoes_input() {
+    let config = Config {
+        eof: EofPolicy::Zero,
+        ..Config::default()
+    };
+
+    let run = run_executable("echo", b",[.,]", &config, b"echo");
+
+    assert_eq!(run.output, b"echo");
+    assert_eq!(run.code, Some(0));
+}
+
+#[test]
+fn reports_pointer_off_tape() {
+    let run = run_executable("off_tape", b"+.\n<", &Config::default(), b"");
+
+    assert_eq!(run.output, [1]);
+    assert_eq!(run.code, Some(1));
+    assert_eq!(
+        run.stderr,
+        "Error: Tried to move the pointer to cell -1, outside of the tape (0..30000), at 2:1\n"
+    );
+}
+
+#[test]
+fn errors_match_interpreter() {
+    let eof = Config {
+        eof: EofPolicy::Error,
+        ..Config::default()
+    };
+    let overflow = Config {
+        cell_overflow: Overflow::Trap,
+        ..Config::default()
+    };
+    let short = Config {
+        tape_len: 8,
+        ..Config::default()
+    };
+
+    for (i, (source, config)) in [
+        (&b"+.,"[..], &eof),
+        (b"+++.>-", &overflow),
+        (b"[-]\n  +[->+<]>-.-", &overflow),
+        (b"+[->>>>>>>>+<<<<<<<<]", &short),
+    ]
+    .into_iter()
+    .enumerate()
+    {
+        let run = run_executable(&format!("error{i}"), source, config, b"");
+        let (output, error) = run_lir(source, config, b"");
+
+        assert_eq!(run.output, output);
+        assert_eq!(run.code, Some(1));
+        assert_eq!(
+            Some(run.stderr),
+            error.map(|error| format!("Error: {error}\n"))
+        );
+    }
+}
+
+#[test]
+fn reports_failing_to_allocate_the_tape() {
+    let config = Config {
+        tape_len: 1 << 26,
+        ..Config::default()
+    };
+
+    let path = build("no_memory", b"+.", &config);
+
+    // The tape takes 256MiB, more than the address space is limited to
+    let output = Command::new("sh")
+        .arg("-c")
+        .arg(format!("ulimit -v 65536 && exec {}", path.display()))
+        .output()
+        .unwrap();
+
+    assert_eq!(output.stdout, b"");
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(output.stderr, b"Error: Couldn't allocate the tape\n");
+}
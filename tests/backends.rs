@@ -0,0 +1,375 @@
+use std::time::Duration;
+
+use bumpalo::Bump;
+use rustfuck::config::{CellWidth, EofPolicy, Overflow, TapeMode};
+use rustfuck::hir::BfOp;
+use rustfuck::ir::{Span, Spanned};
+use rustfuck::jit::Jit;
+use rustfuck::limit::{Limit, LimitExceeded};
+use rustfuck::state::BrainfuckState;
+use rustfuck::{BackendKind, Config, Program, RuntimeError};
+
+fn backends() -> impl Iterator<Item = BackendKind> {
+    BackendKind::ALL
+        .into_iter()
+        .filter(|&backend| backend != BackendKind::Jit || Jit::is_supported())
+}
+
+/// Runs `source` on `backend` with `input`, returning the state it left behind, what it output and
+/// why it stopped, if it failed
+fn run(
+    source: &[u8],
+    backend: BackendKind,
+    config: &Config,
+    input: &[u8],
+) -> (BrainfuckState, Vec<u8>, Result<(), RuntimeError>) {
+    let arena = Bump::new();
+    let program = Program::compile(source, backend, config, &arena).unwrap();
+
+    let mut state = BrainfuckState::new();
+    let mut output = Vec::new();
+    let result = program
+        .run(&mut state, &mut &input[..], &mut output)
+        .map_err(|err| err.downcast().unwrap());
+
+    (state, output, result)
+}
+
+#[test]
+fn deeply_nested_loops() {
+    // Deeper than the call stack could manage if loops were handled recursively
+    let depth = 100_000;
+    let source = format!("+{}{}+.", "[-".repeat(depth), "]".repeat(depth));
+
+    for backend in backends() {
+        let arena = Bump::new();
+        let program =
+            Program::compile(source.as_bytes(), backend, &Config::default(), &arena).unwrap();
+
+        let mut output = Vec::new();
+        program
+            .run(&mut BrainfuckState::new(), &mut &b""[..], &mut output)
+            .unwrap();
+
+        assert_eq!(output, [1], "{backend:?}");
+    }
+}
+
+#[test]
+fn unbalanced_loops() {
+    // The parser won't produce these, but anyone can build a program by hand
+    let open = Span {
+        start: 3,
+        end: 4,
+        line: 1,
+        column: 4,
+    };
+    let programs = [
+        vec![Spanned::new(BfOp::BrFor, open)],
+        vec![Spanned::new(BfOp::BrBack, open)],
+        vec![
+            Spanned::new(BfOp::BrFor, Span::default()),
+            Spanned::new(BfOp::BrBack, Span::default()),
+            Spanned::new(BfOp::BrFor, open),
+        ],
+    ];
+
+    // The BF interpreter runs brackets as it finds them, so only notices when it has to jump
+    for backend in backends().filter(|&backend| backend != BackendKind::Bf) {
+        for program in &programs {
+            let arena = Bump::new();
+
+            // Some backends only look for the matching bracket when they run
+            let result = backend
+                .compile(program, &Config::default(), &arena)
+                .and_then(|compiled| {
+                    compiled.run(&mut BrainfuckState::new(), &mut &b""[..], &mut Vec::new())
+                });
+
+            let err = result.expect_err("unbalanced loops must fail");
+            assert!(
+                matches!(
+                    err.downcast_ref(),
+                    Some(&RuntimeError::UnmatchedBracket { span }) if span == open
+                ),
+                "{backend:?} failed with {err}"
+            );
+        }
+    }
+}
+
+#[test]
+fn eof_policies() {
+    for backend in backends() {
+        for cell_width in [CellWidth::U8, CellWidth::U16, CellWidth::U32] {
+            for (eof, expected) in [
+                (EofPolicy::Unchanged, 3),
+                (EofPolicy::Zero, 0),
+                (EofPolicy::MinusOne, cell_width.max()),
+            ] {
+                let config = Config {
+                    eof,
+                    cell_width,
+                    ..Config::default()
+                };
+                let context = format!("{backend:?} with {eof:?} and {cell_width:?}");
+
+                // Input is read as usual until it runs out
+                let (state, _, result) = run(b",>+++,", backend, &config, b"a");
+                assert!(result.is_ok(), "{context}");
+                assert_eq!(state.read_cell(state.pos - 1), u32::from(b'a'), "{context}");
+                assert_eq!(state.read_cur_cell(), expected, "{context}");
+            }
+        }
+
+        let config = Config {
+            eof: EofPolicy::Error,
+            ..Config::default()
+        };
+
+        let (_, _, result) = run(b",>+++,", backend, &config, b"a");
+        assert!(
+            matches!(
+                result,
+                Err(RuntimeError::Eof { span }) if (span.line, span.column) == (1, 6)
+            ),
+            "{backend:?} finished with {result:?}"
+        );
+    }
+}
+
+#[test]
+fn cell_widths_wrap() {
+    let sixteen = "+".repeat(16);
+    // 257 in the second cell, then moved to the third while adding 256 times it to the fourth
+    let source = format!("{sixteen}[->{sixteen}<]>+.[->+>{}<<]>>-.", "+".repeat(256));
+
+    for backend in backends() {
+        for (cell_width, second, third) in [
+            (CellWidth::U8, 1, 255),
+            (CellWidth::U16, 257, 255),
+            (CellWidth::U32, 257, 65791),
+        ] {
+            let config = Config {
+                cell_width,
+                ..Config::default()
+            };
+            let context = format!("{backend:?} with {cell_width:?}");
+
+            let (state, output, result) = run(source.as_bytes(), backend, &config, b"");
+            assert!(result.is_ok(), "{context}");
+
+            // Only the low byte of a cell is output
+            assert_eq!(output, [1, 255], "{context}");
+            assert_eq!(state.read_cell(1), 0, "{context}");
+            assert_eq!(state.read_cell(2), second, "{context}");
+            assert_eq!(state.read_cell(3), third, "{context}");
+        }
+
+        // Going below zero wraps to the largest value the cell holds
+        for cell_width in [CellWidth::U8, CellWidth::U16, CellWidth::U32] {
+            let config = Config {
+                cell_width,
+                ..Config::default()
+            };
+            let context = format!("{backend:?} with {cell_width:?}");
+
+            let (state, output, _) = run(b"-.", backend, &config, b"");
+            assert_eq!(output, [255], "{context}");
+            assert_eq!(state.read_cur_cell(), cell_width.max(), "{context}");
+        }
+    }
+}
+
+#[test]
+fn cell_overflow() {
+    let plus = |n| "+".repeat(n);
+
+    // Each program outputs its cells as it goes. Along with what is output with cells wrapping and
+    // saturating is the column trapping stops at, on backends which run each command in a loop
+    // and on those which run the loop as one op. Trapping only gets as far as the first output
+    let programs: [(_, &[u8], &[u8], _); 5] = [
+        // Plain adds, past either end
+        (".-.".to_owned(), &[0, 255], &[0, 0], (2, 2)),
+        (
+            format!("{}.+.", plus(255)),
+            &[255, 0],
+            &[255, 255],
+            (257, 257),
+        ),
+        // `MoveCell`, in either direction
+        (
+            format!("{}.>+<[->+<]>.", plus(255)),
+            &[255, 0],
+            &[255, 255],
+            (263, 260),
+        ),
+        (
+            format!("{}.>+<[->-<]>.", plus(10)),
+            &[10, 247],
+            &[10, 0],
+            (18, 15),
+        ),
+        // `MulAdd`, with a target going each way
+        (
+            format!("{}.[->++>-<<]>.>.", plus(200)),
+            &[200, 144, 56],
+            &[200, 255, 0],
+            (208, 202),
+        ),
+    ];
+
+    for backend in backends() {
+        let folds_loops = matches!(backend, BackendKind::Lir | BackendKind::Jit);
+
+        for (source, wrapped, saturated, (column, folded_column)) in &programs {
+            let context = format!("{backend:?} on {source}");
+
+            for (cell_overflow, expected) in
+                [(Overflow::Wrap, wrapped), (Overflow::Saturate, saturated)]
+            {
+                let config = Config {
+                    cell_overflow,
+                    ..Config::default()
+                };
+
+                let (_, output, result) = run(source.as_bytes(), backend, &config, b"");
+                assert!(result.is_ok(), "{context} with {cell_overflow:?}");
+                assert_eq!(output, *expected, "{context} with {cell_overflow:?}");
+            }
+
+            let config = Config {
+                cell_overflow: Overflow::Trap,
+                ..Config::default()
+            };
+            let column = if folds_loops { folded_column } else { column };
+
+            let (_, output, result) = run(source.as_bytes(), backend, &config, b"");
+            assert_eq!(output, wrapped[..1], "{context}");
+            assert!(
+                matches!(
+                    result,
+                    Err(RuntimeError::CellOverflow { span })
+                        if (span.line, span.column) == (1, *column)
+                ),
+                "{context} finished with {result:?}"
+            );
+        }
+    }
+}
+
+#[test]
+fn limits_stop_endless_loops() {
+    let time = Duration::from_millis(50);
+
+    for backend in backends() {
+        for (config, expected) in [
+            (
+                Config {
+                    step_limit: Some(1000),
+                    ..Config::default()
+                },
+                Limit::Steps(1000),
+            ),
+            (
+                Config {
+                    time_limit: Some(time),
+                    ..Config::default()
+                },
+                Limit::Time(time),
+            ),
+        ] {
+            let (_, _, result) = run(b"+[]", backend, &config, b"");
+
+            // Every backend stops at the `]`, about to go around again
+            assert!(
+                matches!(
+                    result,
+                    Err(RuntimeError::LimitExceeded(LimitExceeded { limit, span, pointer: 0 }))
+                        if limit == expected && (span.line, span.column) == (1, 3)
+                ),
+                "{backend:?} finished with {result:?}"
+            );
+        }
+    }
+}
+
+#[test]
+fn infinite_tape_stops_at_max_len() {
+    let config = Config {
+        tape: TapeMode::Infinite,
+        max_tape_len: 64,
+        ..Config::default()
+    };
+
+    for backend in backends() {
+        // Using 61 of the cells is fine, in either direction
+        let (_, output, result) = run(
+            format!("{}+{}+.", ">".repeat(30), "<".repeat(60)).as_bytes(),
+            backend,
+            &config,
+            b"",
+        );
+        assert!(result.is_ok(), "{backend:?} finished with {result:?}");
+        assert_eq!(output, [1], "{backend:?}");
+
+        // Backends grow the tape by different amounts, so may need more than one more cell when
+        // they find they've run out
+        for source in [
+            &b"+[>+]"[..],
+            b"+[<+]",
+            ">".repeat(100).as_bytes(),
+            "<".repeat(100).as_bytes(),
+        ] {
+            let context = format!("{backend:?} on {}", String::from_utf8_lossy(source));
+
+            let (_, _, result) = run(source, backend, &config, b"");
+            let Err(err @ RuntimeError::TapeTooLarge { len, max: 64, .. }) = result else {
+                panic!("{context} finished with {result:?}");
+            };
+
+            assert!(len > 64, "{context}");
+            assert!(err.span().is_some(), "{context}");
+            assert!(
+                err.to_string().starts_with(&format!(
+                    "The tape would need {len} cells, more than the maximum of 64, at 1:"
+                )),
+                "{context} failed with {err}"
+            );
+        }
+    }
+}
+
+#[test]
+fn errors_point_at_failing_command() {
+    let trap = Config {
+        cell_overflow: Overflow::Trap,
+        ..Config::default()
+    };
+
+    for backend in backends() {
+        // Columns count characters, so the tab is one
+        let (_, output, result) = run(b"+.\n\t <", backend, &Config::default(), b"");
+        assert_eq!(output, [1], "{backend:?}");
+        assert!(
+            matches!(
+                result,
+                Err(RuntimeError::PointerOutOfRange { span, cell: -1, len: 30000 })
+                    if (span.line, span.column) == (2, 3)
+            ),
+            "{backend:?} finished with {result:?}"
+        );
+
+        let (_, output, result) = run(b"++.\n  --.\n   -", backend, &trap, b"");
+        assert_eq!(output, [2, 0], "{backend:?}");
+
+        let err = result.unwrap_err();
+        let span = err.span().expect("overflow has a span");
+        assert!(
+            matches!(err, RuntimeError::CellOverflow { .. }),
+            "{backend:?} failed with {err}"
+        );
+        assert_eq!((span.line, span.column), (3, 4), "{backend:?}");
+        assert_eq!(err.to_string(), "Cell overflowed at 3:4", "{backend:?}");
+    }
+}
@@ -0,0 +1,266 @@
+//! Builds translated programs with the tools they are meant for and runs them, checking they behave
+//! like the LIR interpreter. Each test is skipped if its tools aren't installed
+
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use bumpalo::Bump;
+use rustfuck::config::{CellWidth, EofPolicy, Overflow, TapeMode};
+use rustfuck::emit::EmitKind;
+use rustfuck::parser::BfParser;
+use rustfuck::state::BrainfuckState;
+use rustfuck::{BackendKind, Config, Program};
+
+/// What a program output, and the message it failed with if it did
+type Outcome = (Vec<u8>, Option<String>);
+
+/// A program to run, with the config to translate it under and what to give it as input
+struct Case {
+    source: &'static [u8],
+    config: Config,
+    input: &'static [u8],
+}
+
+/// Programs covering each op, under configs covering each way they can behave or fail
+///
+/// Translations start with all of an infinite tape rather than growing it, so they describe running
+/// out of room differently, and none of these do
+fn cases() -> Vec<Case> {
+    let with = |source, config| Case {
+        source,
+        config,
+        input: b"",
+    };
+    let fixed = |tape_len, pointer_overflow| Config {
+        tape_len,
+        pointer_overflow,
+        ..Config::default()
+    };
+    let cells = |cell_width, cell_overflow| Config {
+        cell_width,
+        cell_overflow,
+        ..Config::default()
+    };
+    let eof = |eof| Config {
+        eof,
+        ..Config::default()
+    };
+
+    // Sixteen times eighteen, which is too much for a byte
+    let product = b"++++++++++++++++[->++++++++++++++++++<]>.";
+
+    vec![
+        with(
+            include_bytes!("../examples/hello_world.b"),
+            Config::default(),
+        ),
+        with(include_bytes!("../examples/add.b"), Config::default()),
+        // Finishing with a move leaves the pointer written but never read again
+        with(b"+++.>", Config::default()),
+        with(b"+[>+<-]>[<]>", Config::default()),
+        with(b"+>+>+<<[>]+.", Config::default()),
+        // Cell overflow, in plain adds, `MoveCell` and `MulAdd`
+        with(product, cells(CellWidth::U8, Overflow::Wrap)),
+        with(product, cells(CellWidth::U8, Overflow::Saturate)),
+        with(product, cells(CellWidth::U8, Overflow::Trap)),
+        with(product, cells(CellWidth::U16, Overflow::Trap)),
+        with(b"+++.\n>-", cells(CellWidth::U8, Overflow::Trap)),
+        with(b"-.>-[->+<]>.", cells(CellWidth::U16, Overflow::Saturate)),
+        with(b"-[->+<]>+.", cells(CellWidth::U32, Overflow::Trap)),
+        with(b"-.[->+<]>+.", cells(CellWidth::U32, Overflow::Wrap)),
+        // Pointer overflow, moving and reaching away from the pointer
+        with(b"+.\n<", fixed(8, Overflow::Trap)),
+        with(b"+[->>>>>>>>+<<<<<<<<]", fixed(8, Overflow::Trap)),
+        with(b"+<+.>>>>>>>>.", fixed(8, Overflow::Wrap)),
+        with(b"+[-<<+>>]<<.>>>>>>>>>>+.", fixed(8, Overflow::Saturate)),
+        with(
+            b"+<<<<+>>>>>>>>+[<]",
+            Config {
+                tape: TapeMode::Infinite,
+                max_tape_len: 64,
+                ..Config::default()
+            },
+        ),
+        // End of input
+        Case {
+            input: b"echo",
+            ..with(b",[.,]", eof(EofPolicy::Zero))
+        },
+        with(b"+,.", eof(EofPolicy::Zero)),
+        with(b"+,.", eof(EofPolicy::MinusOne)),
+        with(b"+,.", eof(EofPolicy::Unchanged)),
+        with(b"+.\n ,", eof(EofPolicy::Error)),
+    ]
+}
+
+/// Runs `source` on the LIR interpreter, for the translation to be compared against
+fn run_lir(source: &[u8], config: &Config, input: &[u8]) -> Outcome {
+    let arena = Bump::new();
+    let program = Program::compile(source, BackendKind::Lir, config, &arena).unwrap();
+
+    let mut output = Vec::new();
+    let result = program.run(&mut BrainfuckState::new(), &mut &input[..], &mut output);
+
+    (output, result.err().map(|err| err.to_string()))
+}
+
+/// Runs `command`, giving it `input`. Translated programs exit with 1 and report why on stderr,
+/// the same way rustfuck itself does
+fn run_native(command: &mut Command, input: &[u8]) -> Outcome {
+    let mut child = command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+
+    child.stdin.take().unwrap().write_all(input).unwrap();
+
+    let output = child.wait_with_output().unwrap();
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    let error = stderr
+        .strip_prefix("Error: ")
+        .map(|message| message.trim_end().to_owned());
+
+    match error {
+        Some(_) => assert_eq!(output.status.code(), Some(1), "{stderr}"),
+        None => assert!(output.status.success(), "{stderr}"),
+    }
+
+    (output.stdout, error)
+}
+
+/// Whether `tool` can be run, so the tests needing it can be skipped when it isn't installed
+fn installed(tool: &str) -> bool {
+    Command::new(tool)
+        .arg("--version")
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .is_ok_and(|status| status.success())
+}
+
+/// A directory for `name`'s files, kept apart from every other test's
+fn scratch(name: &str) -> PathBuf {
+    let dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join(name);
+    fs::create_dir_all(&dir).unwrap();
+
+    dir
+}
+
+/// Runs `command`, which builds a translated program, and checks it succeeded
+fn build(mut command: Command) {
+    let built = command.output().unwrap();
+
+    assert!(
+        built.status.success(),
+        "{command:?}: {}",
+        String::from_utf8_lossy(&built.stderr)
+    );
+}
+
+/// Translates each of [`cases`] into a file in `dir` ending in `extension`, and checks the command
+/// `runner` returns for it behaves like the interpreter. `runner` builds anything it has to first
+fn check(kind: EmitKind, dir: &Path, extension: &str, runner: impl Fn(&Path) -> Command) {
+    for (i, case) in cases().into_iter().enumerate() {
+        let program = BfParser::parse(case.source).unwrap();
+        let file = dir.join(format!("case{i}.{extension}"));
+        fs::write(&file, kind.emit(&program, &case.config).unwrap()).unwrap();
+
+        assert_eq!(
+            run_native(&mut runner(&file), case.input),
+            run_lir(case.source, &case.config, case.input),
+            "case {i} with {:?}",
+            case.config
+        );
+    }
+}
+
+#[test]
+fn rust_matches_interpreter() {
+    if !installed("rustc") {
+        return;
+    }
+
+    // Denying warnings holds the translation to what a crate including it might
+    check(EmitKind::Rust, &scratch("rust"), "rs", |file| {
+        let main = file.with_extension("main.rs");
+        let include = file.file_name().unwrap().to_str().unwrap();
+
+        fs::write(
+            &main,
+            format!(
+                r#"mod program {{
+    include!("{include}");
+}}
+
+fn main() {{
+    if let Err(err) = program::run(&mut std::io::stdin().lock(), &mut std::io::stdout().lock()) {{
+        eprintln!("Error: {{err}}");
+        std::process::exit(1);
+    }}
+}}
+"#
+            ),
+        )
+        .unwrap();
+
+        let executable = file.with_extension("");
+        let mut rustc = Command::new("rustc");
+        rustc
+            .args(["--crate-name", "main", "-D", "warnings", "-o"])
+            .arg(&executable)
+            .arg(main);
+        build(rustc);
+
+        Command::new(executable)
+    });
+}
+
+#[test]
+fn c_matches_interpreter() {
+    if !installed("cc") {
+        return;
+    }
+
+    check(EmitKind::C, &scratch("c"), "c", |file| {
+        let executable = file.with_extension("");
+        let mut cc = Command::new("cc");
+        cc.arg("-o").arg(&executable).arg(file);
+        build(cc);
+
+        Command::new(executable)
+    });
+}
+
+#[test]
+fn llvm_matches_interpreter() {
+    let Ok(version) = Command::new("lli").arg("--version").output() else {
+        return;
+    };
+
+    // The IR uses opaque pointers, which LLVM 14 has to be asked for and earlier versions lack
+    let version = String::from_utf8_lossy(&version.stdout);
+    let major = version
+        .split("version ")
+        .nth(1)
+        .and_then(|rest| rest.split('.').next())
+        .and_then(|major| major.parse::<u32>().ok());
+
+    let Some(major) = major.filter(|&major| major >= 14) else {
+        return;
+    };
+
+    check(EmitKind::Llvm, &scratch("llvm"), "ll", |file| {
+        let mut lli = Command::new("lli");
+
+        if major < 15 {
+            lli.arg("-opaque-pointers");
+        }
+
+        lli.arg(file);
+        lli
+    });
+}
@@ -0,0 +1,225 @@
+//! The JIT elides bounds checks it can prove are unnecessary, so check it against the LIR
+//! interpreter, which checks every access
+
+use bumpalo::Bump;
+use rustfuck::config::{Overflow, TapeMode};
+use rustfuck::ir::{Span, Spanned};
+use rustfuck::jit::Jit;
+use rustfuck::lir::LirOp;
+use rustfuck::state::BrainfuckState;
+use rustfuck::{BackendKind, Config, Program, RuntimeError};
+
+/// What running a program left behind: its output, how it finished, where the pointer ended up
+/// and the non-zero cells by their position on the tape
+#[derive(Debug, PartialEq, Eq)]
+struct Outcome {
+    output: Vec<u8>,
+    result: Result<(), String>,
+    pointer: isize,
+    cells: Vec<(isize, u32)>,
+}
+
+fn run(source: &[u8], backend: BackendKind, config: &Config) -> Outcome {
+    let arena = Bump::new();
+    let program = Program::compile(source, backend, config, &arena).unwrap();
+
+    let mut state = BrainfuckState::new();
+    let mut output = Vec::new();
+    // The interpreters count steps differently to the JIT, and grow an infinite tape at different
+    // points and by different amounts, so only whether a limit was hit can be compared
+    let result = program
+        .run(&mut state, &mut &b""[..], &mut output)
+        .map_err(|err| match err.downcast_ref() {
+            Some(RuntimeError::LimitExceeded(_)) => "limit exceeded".to_owned(),
+            Some(RuntimeError::TapeTooLarge { .. }) => "tape too large".to_owned(),
+            _ => err.to_string(),
+        });
+
+    let cells = state
+        .cells
+        .iter()
+        .enumerate()
+        .filter(|&(_, &cell)| cell != 0)
+        .map(|(i, &cell)| (i as isize - state.origin as isize, cell))
+        .collect();
+
+    Outcome {
+        output,
+        result,
+        pointer: state.pointer(),
+        cells,
+    }
+}
+
+/// Runs `source` on both backends and checks they agree
+fn compare(source: &[u8], config: &Config) {
+    let lir = run(source, BackendKind::Lir, config);
+    let jit = run(source, BackendKind::Jit, config);
+    let context = format!("{} with {config:?}", String::from_utf8_lossy(source));
+
+    // The backends check accesses at different points, so a program which traps can leave the
+    // tape in a different state on each. Only what it output and why it stopped has to match
+    if lir.result.is_err() {
+        assert_eq!(
+            (&lir.output, &lir.result),
+            (&jit.output, &jit.result),
+            "{context}"
+        );
+    } else {
+        assert_eq!(lir, jit, "{context}");
+    }
+}
+
+/// Every policy for a tape of `len` cells, plus an infinite tape
+///
+/// Backends lay an infinite tape out differently, so a program may run into its maximum length on
+/// one before another, and it is left at the default to keep out of the way
+///
+/// A wrapping or saturating pointer can hop along a full tape forever, so programs are cut short
+/// by a step limit
+fn configs(len: usize) -> Vec<Config> {
+    let base = Config {
+        step_limit: Some(100_000),
+        ..Config::default()
+    };
+
+    let mut configs: Vec<_> = [Overflow::Trap, Overflow::Wrap, Overflow::Saturate]
+        .into_iter()
+        .map(|pointer_overflow| Config {
+            pointer_overflow,
+            tape_len: len,
+            ..base
+        })
+        .collect();
+
+    configs.push(Config {
+        tape: TapeMode::Infinite,
+        ..base
+    });
+
+    configs
+}
+
+#[test]
+fn move_cell_from_zero_cell_skips_target() {
+    if !Jit::is_supported() {
+        return;
+    }
+
+    // The target is far off the tape, but never touched as the loop doesn't run
+    let far = 10_000_000;
+    let mut source = b"[-".to_vec();
+    source.extend(std::iter::repeat_n(b'>', far));
+    source.push(b'+');
+    source.extend(std::iter::repeat_n(b'<', far));
+    source.push(b']');
+
+    let outcome = run(&source, BackendKind::Jit, &Config::default());
+    assert_eq!(outcome.result, Ok(()));
+}
+
+#[test]
+fn tape_edges_match_interpreter() {
+    if !Jit::is_supported() {
+        return;
+    }
+
+    let len = 8;
+    let programs: &[&[u8]] = &[
+        // `OffsetModify` past either end, from either end and the middle
+        b"+>>>>>>>>>>+<<<<<<<<<<.",
+        b"+<<+>>.",
+        b">>>>>>>+>>+<<.",
+        b">>>>+<<<<<<+>>.",
+        // `MoveCell`
+        b"+[->>>>>>>>>>+<<<<<<<<<<]",
+        b"+[-<<+>>]",
+        b">>>>>>>+[->>+<<]",
+        b"[->>>>>>>>>>+<<<<<<<<<<]",
+        b"[-<<+>>]",
+        // `MulAdd`
+        b"+++[->>>>>>>>>>++<<<<<<<<<<]",
+        b"+++[-<<+++>>]",
+        b">>>>+++[->>>>>++<<<<<<<<<+++>>>>]",
+        b"[->>>>>>>>>>++<<<<<<<<<<]",
+        // `Hop` off either end
+        b"+>+>+>+>+>+>+>+<<<<<<<[>]+.",
+        b"+[<]+.",
+        b">>>>+<+<+<+<+[<]+.",
+        b"+>>+>>+>>+>>+<<<<<<<<[>>]+.",
+        // Loops which never move the pointer, so are checked before entering
+        b"++[->>>>>>>>>>+<<<<<<<<<<<<<<+>>>>]",
+        b">>>>+++[-<<<<<+>>>>>>>>>>-<<<<<]",
+    ];
+
+    for config in configs(len) {
+        for source in programs {
+            compare(source, &config);
+        }
+    }
+}
+
+#[test]
+fn infinite_tape_grows_like_interpreter() {
+    if !Jit::is_supported() {
+        return;
+    }
+
+    // Far enough to run past the room the JIT starts with either side of the pointer
+    let far = 100_000;
+    let left = "<".repeat(far);
+    let right = ">".repeat(far);
+
+    let programs = [
+        format!("+{left}+{right}{right}+{left}."),
+        format!("+[-{right}+{left}]"),
+        format!("+++[-{left}++{right}{right}+++{left}]"),
+    ];
+
+    let config = Config {
+        tape: TapeMode::Infinite,
+        ..Config::default()
+    };
+
+    for source in programs {
+        compare(source.as_bytes(), &config);
+    }
+}
+
+#[test]
+fn reports_unbalanced_loops() {
+    if !Jit::is_supported() {
+        return;
+    }
+
+    // `LirGen` only produces balanced programs, but LIR can be built by hand
+    let open = Span {
+        start: 3,
+        end: 4,
+        line: 1,
+        column: 4,
+    };
+    let programs = [
+        vec![Spanned::new(LirOp::BrFor, open)],
+        vec![Spanned::new(LirOp::BrBack, open)],
+        vec![
+            Spanned::new(LirOp::BrFor, Span::default()),
+            Spanned::new(LirOp::BrBack, Span::default()),
+            Spanned::new(LirOp::BrFor, open),
+        ],
+    ];
+
+    for program in programs {
+        let err = Jit::jit(&program, &Config::default())
+            .err()
+            .expect("unbalanced loops must fail");
+
+        assert!(
+            matches!(
+                err.downcast_ref(),
+                Some(&RuntimeError::UnmatchedBracket { span }) if span == open
+            ),
+            "failed with {err}"
+        );
+    }
+}
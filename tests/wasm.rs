@@ -0,0 +1,184 @@
+//! Runs translated programs in a WebAssembly runtime, holding them to the layout `EmitKind::Wasm`
+//! documents
+
+use std::collections::VecDeque;
+
+use bumpalo::Bump;
+use rustfuck::config::{CellWidth, EofPolicy, Overflow, TapeMode};
+use rustfuck::emit::EmitKind;
+use rustfuck::parser::BfParser;
+use rustfuck::state::BrainfuckState;
+use rustfuck::{BackendKind, Config, Program};
+use wasmi::{Caller, Engine, Linker, Module, Store};
+
+/// What the module's imports read from and write to
+struct Io {
+    input: VecDeque<u8>,
+    output: Vec<u8>,
+}
+
+/// What running a translated program left behind
+struct Run {
+    output: Vec<u8>,
+    /// The message `run` returned, if the program failed
+    error: Option<String>,
+    memory: Vec<u8>,
+}
+
+fn run_wasm(source: &[u8], config: &Config, input: &[u8]) -> Run {
+    let program = BfParser::parse(source).unwrap();
+    let wasm = EmitKind::Wasm.emit(&program, config).unwrap();
+
+    // Parsing the module validates it too
+    let engine = Engine::default();
+    let module = Module::new(&engine, &wasm[..]).unwrap();
+
+    let io = Io {
+        input: input.iter().copied().collect(),
+        output: Vec::new(),
+    };
+    let mut store = Store::new(&engine, io);
+
+    let mut linker = Linker::<Io>::new(&engine);
+    linker
+        .func_wrap("env", "getchar", |mut caller: Caller<'_, Io>| -> i32 {
+            caller.data_mut().input.pop_front().map_or(-1, i32::from)
+        })
+        .unwrap();
+    linker
+        .func_wrap("env", "putchar", |mut caller: Caller<'_, Io>, c: i32| {
+            caller.data_mut().output.push(c as u8);
+        })
+        .unwrap();
+
+    let instance = linker
+        .instantiate(&mut store, &module)
+        .unwrap()
+        .start(&mut store)
+        .unwrap();
+
+    let run = instance.get_typed_func::<(), i32>(&store, "run").unwrap();
+    let result = run.call(&mut store, ()).unwrap();
+
+    let memory = instance
+        .get_memory(&store, "memory")
+        .expect("`memory` is exported")
+        .data(&store)
+        .to_vec();
+
+    // Errors are a little endian `u32` length, followed by that many bytes of UTF-8
+    let error = (result != 0).then(|| {
+        let address = result as usize;
+        let len = u32::from_le_bytes(memory[address..address + 4].try_into().unwrap()) as usize;
+
+        String::from_utf8(memory[address + 4..address + 4 + len].to_vec()).unwrap()
+    });
+
+    Run {
+        output: store.into_data().output,
+        error,
+        memory,
+    }
+}
+
+/// Runs `source` on the LIR interpreter, for the translation to be compared against
+fn run_lir(source: &[u8], config: &Config, input: &[u8]) -> (Vec<u8>, Option<String>) {
+    let arena = Bump::new();
+    let program = Program::compile(source, BackendKind::Lir, config, &arena).unwrap();
+
+    let mut output = Vec::new();
+    let result = program.run(&mut BrainfuckState::new(), &mut &input[..], &mut output);
+
+    (output, result.err().map(|err| err.to_string()))
+}
+
+#[test]
+fn hello_world() {
+    let source = include_bytes!("../examples/hello_world.b");
+    let config = Config::default();
+
+    let run = run_wasm(source, &config, b"");
+    assert_eq!(run.error, None);
+    assert_eq!(run.output, b"Hello World!\n");
+    assert_eq!((run.output, run.error), run_lir(source, &config, b""));
+}
+
+#[test]
+fn echoes_input() {
+    // Clearing each cell before reading the next stops either way at the end of input
+    let source = b",[.[-],]";
+
+    for eof in [EofPolicy::Unchanged, EofPolicy::Zero] {
+        let config = Config {
+            eof,
+            ..Config::default()
+        };
+
+        let run = run_wasm(source, &config, b"echo");
+        assert_eq!(run.output, b"echo");
+        assert_eq!(run.error, None);
+    }
+}
+
+#[test]
+fn tape_is_at_start_of_memory() {
+    let source = b"+++>++>->";
+
+    let run = run_wasm(source, &Config::default(), b"");
+    assert_eq!(run.memory[..4], [3, 2, 255, 0]);
+
+    // Wider cells take up more bytes, in little endian order
+    let config = Config {
+        cell_width: CellWidth::U16,
+        ..Config::default()
+    };
+    let run = run_wasm(source, &config, b"");
+    assert_eq!(run.memory[..8], [3, 0, 2, 0, 255, 255, 0, 0]);
+
+    // The messages come after the tape, so the memory holds at least all of it
+    assert!(run.memory.len() >= config.tape_len * 2);
+
+    // An infinite tape starts with the pointer in the middle
+    let config = Config {
+        tape: TapeMode::Infinite,
+        max_tape_len: 16,
+        ..Config::default()
+    };
+    let run = run_wasm(b"+<++", &config, b"");
+    assert_eq!(run.memory[7..9], [2, 1]);
+}
+
+#[test]
+fn errors_match_interpreter() {
+    let eof = Config {
+        eof: EofPolicy::Error,
+        ..Config::default()
+    };
+    let overflow = Config {
+        cell_overflow: Overflow::Trap,
+        ..Config::default()
+    };
+
+    for (source, config) in [
+        (&b"+.,"[..], &eof),
+        (b"+++.>-", &overflow),
+        (b"[-]\n  +[->+<]>-.-", &overflow),
+    ] {
+        let run = run_wasm(source, config, b"");
+        let (output, error) = run_lir(source, config, b"");
+
+        assert!(run.error.is_some());
+        assert_eq!((run.output, run.error), (output, error));
+    }
+}
+
+#[test]
+fn reports_pointer_off_tape() {
+    let run = run_wasm(b"+.\n<", &Config::default(), b"");
+
+    assert_eq!(run.output, [1]);
+    assert_eq!(
+        run.error.as_deref(),
+        Some("Tried to move the pointer outside of the tape (0..30000), at 2:1")
+    );
+}